@@ -0,0 +1,129 @@
+use crate::state::EcdsaPublicKey;
+use bech32::{u5, ToBase32, Variant};
+use candid::Principal;
+use hmac::{Hmac, Mac};
+use ic_btc_interface::Network;
+use ic_ledger_types::Subaccount;
+use libsecp256k1::{PublicKey, SecretKey};
+use sha2::{Digest, Sha256, Sha512};
+
+/// The version of the derivation path schema used by the ckBTC minter.
+const DERIVATION_SCHEMA_V1: u8 = 1;
+
+/// Derive the BTC deposit address of an account in the same way as `get_btc_address` of
+/// the ckBTC minter does, from the ECDSA public key of the minter.
+///
+/// The minter derives a P2WPKH address from the public key at the derivation path
+/// `[schema, owner, subaccount]` of its ECDSA key.
+pub fn derive_btc_deposit_address(
+    minter_public_key: &EcdsaPublicKey,
+    network: Network,
+    owner: &Principal,
+    subaccount: &Subaccount,
+) -> Result<String, String> {
+    let mut public_key = PublicKey::parse_slice(&minter_public_key.public_key, None)
+        .map_err(|e| format!("invalid ECDSA public key of the ckBTC minter: {:?}", e))?;
+    let mut chain_code: [u8; 32] = minter_public_key
+        .chain_code
+        .as_slice()
+        .try_into()
+        .map_err(|_| "invalid chain code of the ckBTC minter".to_string())?;
+    for index in [
+        &[DERIVATION_SCHEMA_V1][..],
+        owner.as_slice(),
+        &subaccount.0[..],
+    ] {
+        (public_key, chain_code) = derive_child_public_key(&public_key, &chain_code, index);
+    }
+    p2wpkh_address(network, &public_key.serialize_compressed())
+}
+
+/// The public key derivation of the extended BIP-32 scheme of the Internet Computer,
+/// which allows arbitrary byte strings as indices.
+fn derive_child_public_key(
+    public_key: &PublicKey,
+    chain_code: &[u8; 32],
+    index: &[u8],
+) -> (PublicKey, [u8; 32]) {
+    let mut input = public_key.serialize_compressed().to_vec();
+    loop {
+        let mut hmac =
+            Hmac::<Sha512>::new_from_slice(chain_code).expect("HMAC accepts keys of any size");
+        hmac.update(&input);
+        hmac.update(index);
+        let output = hmac.finalize().into_bytes();
+        let mut offset = [0u8; 32];
+        offset.copy_from_slice(&output[..32]);
+        let mut next_chain_code = [0u8; 32];
+        next_chain_code.copy_from_slice(&output[32..]);
+        if let Ok(tweak) = SecretKey::parse(&offset) {
+            let mut child = *public_key;
+            if child.tweak_add_assign(&tweak).is_ok() {
+                return (child, next_chain_code);
+            }
+        }
+        // The offset is not a valid scalar or the child key is the point at infinity,
+        // so try again with the next input as defined by SLIP-10.
+        input = [&[1u8][..], &next_chain_code[..]].concat();
+    }
+}
+
+fn p2wpkh_address(network: Network, public_key: &[u8; 33]) -> Result<String, String> {
+    let hrp = match network {
+        Network::Mainnet => "bc",
+        Network::Testnet => "tb",
+        Network::Regtest => "bcrt",
+    };
+    let witness_program = ripemd::Ripemd160::digest(Sha256::digest(public_key));
+    let mut data = vec![u5::try_from_u8(0).expect("0 is a valid witness version")];
+    data.extend(witness_program.to_base32());
+    bech32::encode(hrp, data, Variant::Bech32).map_err(|e| format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_key(hex_key: &str) -> PublicKey {
+        PublicKey::parse_slice(&hex::decode(hex_key).unwrap(), None).unwrap()
+    }
+
+    /// The derivation of `m/0` from the master key of the BIP-32 test vector 2, with the
+    /// index `0` as a 4 bytes index, which is the BIP-32 public derivation.
+    #[test]
+    fn derive_child_public_key_bip32_vector() {
+        let master =
+            public_key("03cbcaa9c98c877a26977d00825c956a238e8dddfbd322cce4f74b0b5bd6ace4a7");
+        let chain_code: [u8; 32] =
+            hex::decode("60499f801b896d83179a4374aeb7822aaeaceaa0db1f85ee3e904c4defbd9689")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let (child, child_chain_code) =
+            derive_child_public_key(&master, &chain_code, &0u32.to_be_bytes());
+        assert_eq!(
+            hex::encode(child.serialize_compressed()),
+            "02fc9e5af0ac8d9b3cecfe2a888e2117ba3d089d8585886c9c826b6b22a98d12ea"
+        );
+        assert_eq!(
+            hex::encode(child_chain_code),
+            "f0909affaa7ee7abe5dd4e100598d4dc53cd709d5a5c2cac40e7412f232f7c9c"
+        );
+    }
+
+    /// The P2WPKH example of BIP-173.
+    #[test]
+    fn p2wpkh_address_bip173_vector() {
+        let public_key =
+            public_key("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .serialize_compressed();
+        assert_eq!(
+            p2wpkh_address(Network::Mainnet, &public_key).unwrap(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert_eq!(
+            p2wpkh_address(Network::Testnet, &public_key).unwrap(),
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+        );
+    }
+}
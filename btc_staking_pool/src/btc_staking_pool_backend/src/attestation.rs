@@ -0,0 +1,97 @@
+use crate::eth_signer::{abi_address, abi_uint, eip712_digest, eip712_domain_separator};
+use crate::keccak256;
+use crate::state::AttestationConfig;
+
+/// The EIP-712 domain name and version of the stake attestations.
+const DOMAIN_NAME: &str = "BTC Staking Pool";
+const DOMAIN_VERSION: &str = "1";
+
+/// The EIP-712 type of a stake attestation.
+const STAKE_ATTESTATION_TYPE: &str =
+    "StakeAttestation(address account,uint256 otbtcBalance,uint256 nonce,uint256 timestamp)";
+
+/// The ABI encoding of `(address account, uint256 otbtcBalance, uint256 nonce,
+/// uint256 timestamp)`.
+pub fn message(account: &[u8; 20], otbtc_balance: u64, nonce: u64, timestamp: u64) -> Vec<u8> {
+    [
+        abi_address(account),
+        abi_uint(otbtc_balance),
+        abi_uint(nonce),
+        abi_uint(timestamp),
+    ]
+    .concat()
+}
+
+/// The EIP-712 hash of an attestation with the ABI encoded `message`, in the domain of the
+/// contract checking it on its chain, which is signed by the pool.
+///
+/// The verifying contract of the configuration must be a valid Ethereum address.
+pub fn digest(config: &AttestationConfig, message: &[u8]) -> [u8; 32] {
+    let verifying_contract: [u8; 20] = hex::decode(&config.verifying_contract)
+        .ok()
+        .and_then(|address| address.try_into().ok())
+        .expect("invalid verifying contract, should not happen");
+    let domain_separator = eip712_domain_separator(
+        DOMAIN_NAME,
+        DOMAIN_VERSION,
+        config.chain_id,
+        &verifying_contract,
+    );
+    let struct_hash =
+        keccak256(&[&keccak256(STAKE_ATTESTATION_TYPE.as_bytes())[..], message].concat());
+    eip712_digest(&domain_separator, &struct_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(chain_id: u64, verifying_contract: &str) -> AttestationConfig {
+        AttestationConfig {
+            chain_id,
+            verifying_contract: verifying_contract.to_string(),
+        }
+    }
+
+    #[test]
+    fn message_is_abi_encoded() {
+        let message = message(&[0xab; 20], 0x1234, 7, 1_700_000_000);
+        assert_eq!(
+            hex::encode(message),
+            [
+                "000000000000000000000000abababababababababababababababababababab",
+                "0000000000000000000000000000000000000000000000000000000000001234",
+                "0000000000000000000000000000000000000000000000000000000000000007",
+                "000000000000000000000000000000000000000000000000000000006553f100",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn digest_of_known_attestation() {
+        let config = config(1, "cccccccccccccccccccccccccccccccccccccccc");
+        let message = message(&[0xab; 20], 0x1234, 7, 1_700_000_000);
+        // The EIP-712 signing hash of the same typed data computed by alloy's `TypedData`.
+        assert_eq!(
+            hex::encode(digest(&config, &message)),
+            "c530236b1316510c189408735fb25d39428aaddfe33c71631fc4217b0e460047"
+        );
+    }
+
+    #[test]
+    fn digest_is_bound_to_domain() {
+        let message = message(&[0xab; 20], 0x1234, 7, 1_700_000_000);
+        let digest_of =
+            |chain_id, verifying_contract| digest(&config(chain_id, verifying_contract), &message);
+        let reference = digest_of(1, "cccccccccccccccccccccccccccccccccccccccc");
+        assert_ne!(
+            reference,
+            digest_of(5, "cccccccccccccccccccccccccccccccccccccccc")
+        );
+        assert_ne!(
+            reference,
+            digest_of(1, "dddddddddddddddddddddddddddddddddddddddd")
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::eth_signer::{self, abi_address, abi_uint};
+use crate::keccak256;
+use crate::state::{self, BridgeVoucher, BtcStakingPoolState, Destination, Operation};
+use ic_ledger_types::Subaccount;
+
+/// The subaccount of the pool which holds the otBTC locked for the ERC-20 tokens on Ethereum.
+///
+/// The hashed input is not 20 bytes long, so it cannot collide with the subaccount of
+/// an Ethereum address.
+pub fn bridge_subaccount() -> Subaccount {
+    Subaccount(keccak256(b"bridge"))
+}
+
+/// The ABI encoding of `(address token, uint256 chain_id, address recipient, uint256 amount,
+/// uint256 nonce)`, which authorizes the ERC-20 contract to mint the amount to the recipient.
+fn voucher_message(
+    erc20_contract: &str,
+    chain_id: u64,
+    recipient: &str,
+    amount: u64,
+    nonce: u64,
+) -> Vec<u8> {
+    let address = |hex_address: &str| -> [u8; 20] {
+        hex::decode(hex_address.trim_start_matches("0x"))
+            .ok()
+            .and_then(|address| address.try_into().ok())
+            .expect("invalid Ethereum address, should not happen")
+    };
+    [
+        abi_address(&address(erc20_contract)),
+        abi_uint(chain_id),
+        abi_address(&address(recipient)),
+        abi_uint(amount),
+        abi_uint(nonce),
+    ]
+    .concat()
+}
+
+/// Record the unsigned mint voucher of a finished bridge operation, with the operation id as
+/// the nonce.
+///
+/// The voucher is for the contract of the operation, so that it does not depend on the bridge
+/// configuration, which may have been changed or removed since the operation was created.
+pub fn record_voucher(state: &mut BtcStakingPoolState, op: &Operation) {
+    let Some(Destination::Bridge {
+        erc20_contract,
+        chain_id,
+    }) = &op.destination
+    else {
+        panic!("bridge contract not found, should not happen");
+    };
+    let message = voucher_message(erc20_contract, *chain_id, &op.eth_address, op.amount, op.id);
+    state.bridge_vouchers.insert(
+        op.id,
+        BridgeVoucher {
+            nonce: op.id,
+            eth_address: op.eth_address.clone(),
+            amount: op.amount,
+            message,
+            signature: None,
+        },
+    );
+}
+
+/// Sign the mint voucher of a bridge operation with the threshold ECDSA key of the pool, unless
+/// it is signed already.
+pub async fn sign_voucher(nonce: u64) -> Result<Option<BridgeVoucher>, String> {
+    let Some((voucher, key_name, public_key)) = state::read_state(|state| {
+        state.bridge_vouchers.get(&nonce).map(|voucher| {
+            (
+                voucher.clone(),
+                state.eth_signer_key_name.clone(),
+                state.eth_signer_public_key.clone(),
+            )
+        })
+    }) else {
+        return Ok(None);
+    };
+    if voucher.signature.is_some() {
+        return Ok(Some(voucher));
+    }
+    let (Some(key_name), Some(public_key)) = (key_name, public_key) else {
+        return Err("the threshold ECDSA key is not configured".to_string());
+    };
+    let signature = eth_signer::sign(&key_name, &public_key, &voucher.message).await?;
+    Ok(state::mutate_state(|state| {
+        state.bridge_vouchers.get_mut(&nonce).map(|voucher| {
+            voucher.signature = Some(signature);
+            voucher.clone()
+        })
+    }))
+}
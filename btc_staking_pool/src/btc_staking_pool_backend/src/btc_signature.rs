@@ -0,0 +1,414 @@
+use crate::errors::VerifySignatureError;
+use bech32::{FromBase32, Variant};
+use ic_btc_interface::Network;
+use libsecp256k1::{Message, PublicKey, RecoveryId, Signature};
+use sha2::{Digest, Sha256};
+
+/// The only output of the `to_sign` transaction of BIP-322: no value, and `OP_RETURN`.
+const TO_SIGN_OUTPUT: [u8; 10] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 0x6a];
+const SIGHASH_DEFAULT: u8 = 0x00;
+const SIGHASH_ALL: u8 = 0x01;
+
+/// A Bitcoin address which can identify a staker.
+pub enum BitcoinAddress {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2tr([u8; 32]),
+}
+
+/// The human-readable part of the segwit addresses of a network, and the version bytes of its
+/// P2PKH and P2SH addresses.
+fn address_prefixes(network: Network) -> (&'static str, u8, u8) {
+    match network {
+        Network::Mainnet => ("bc", 0x00, 0x05),
+        Network::Testnet => ("tb", 0x6f, 0xc4),
+        Network::Regtest => ("bcrt", 0x6f, 0xc4),
+    }
+}
+
+/// Parse a Bitcoin address of a network.
+///
+/// Bech32 addresses must be in lower case, so that an address has a single representation.
+pub fn parse_address(address: &str, network: Network) -> Option<BitcoinAddress> {
+    let (segwit_hrp, p2pkh_version, p2sh_version) = address_prefixes(network);
+    if let Ok((hrp, data, variant)) = bech32::decode(address) {
+        if hrp != segwit_hrp || address != address.to_lowercase() {
+            return None;
+        }
+        let (version, program) = data.split_first()?;
+        let program = Vec::<u8>::from_base32(program).ok()?;
+        return match (version.to_u8(), variant) {
+            (0, Variant::Bech32) => Some(BitcoinAddress::P2wpkh(program.try_into().ok()?)),
+            (1, Variant::Bech32m) => Some(BitcoinAddress::P2tr(program.try_into().ok()?)),
+            _ => None,
+        };
+    }
+    let payload = bs58::decode(address).with_check(None).into_vec().ok()?;
+    let (version, hash) = payload.split_first()?;
+    let hash: [u8; 20] = hash.try_into().ok()?;
+    if *version == p2pkh_version {
+        Some(BitcoinAddress::P2pkh(hash))
+    } else if *version == p2sh_version {
+        Some(BitcoinAddress::P2sh(hash))
+    } else {
+        None
+    }
+}
+
+/// Verify the signature of a message by the owner of a Bitcoin address.
+///
+/// A 65 byte signature with a BIP-137 header byte is verified as a legacy signed message, for
+/// P2PKH, P2SH-P2WPKH and P2WPKH addresses. Any other signature is verified as the witness of
+/// a BIP-322 simple signature, for P2WPKH and P2TR addresses.
+pub fn verify_signature(
+    address: &BitcoinAddress,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), VerifySignatureError> {
+    if signature.len() == 65 && (27..=42).contains(&signature[0]) {
+        verify_bip137_signature(address, message, signature)
+    } else {
+        verify_bip322_signature(address, message, signature)
+    }
+}
+
+fn verify_bip137_signature(
+    address: &BitcoinAddress,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), VerifySignatureError> {
+    let mut data = b"\x18Bitcoin Signed Message:\n".to_vec();
+    write_compact_size(&mut data, message.len());
+    data.extend_from_slice(message);
+    let message = Message::parse(&sha256d(&data));
+    // The header byte encodes the recovery id, and whether the key is compressed. The address
+    // type it also encodes is not enforced, as wallets disagree on it for segwit addresses.
+    let header = signature[0];
+    let recid = RecoveryId::parse((header - 27) & 3)
+        .map_err(|e| VerifySignatureError::InvalidRecoveryIdInSignature(format!("{:?}", e)))?;
+    let signature = Signature::parse_standard_slice(&signature[1..])
+        .map_err(|e| VerifySignatureError::FailedParsingSignature(format!("{:?}", e)))?;
+    let public_key = libsecp256k1::recover(&message, &signature, &recid)
+        .map_err(|e| VerifySignatureError::FailedRecoveringPublicKey(format!("{:?}", e)))?;
+    let compressed = header >= 31;
+    let key_hash = if compressed {
+        hash160(&public_key.serialize_compressed())
+    } else {
+        hash160(&public_key.serialize())
+    };
+    let matches = match address {
+        BitcoinAddress::P2pkh(hash) => *hash == key_hash,
+        BitcoinAddress::P2sh(hash) => {
+            compressed && *hash == hash160(&[&[0x00, 0x14][..], &key_hash[..]].concat())
+        }
+        BitcoinAddress::P2wpkh(hash) => compressed && *hash == key_hash,
+        BitcoinAddress::P2tr(_) => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(VerifySignatureError::SignerAddressMismatch)
+    }
+}
+
+fn verify_bip322_signature(
+    address: &BitcoinAddress,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), VerifySignatureError> {
+    let witness = parse_witness(signature).ok_or_else(|| {
+        VerifySignatureError::InvalidBitcoinSignature("invalid BIP-322 witness".to_string())
+    })?;
+    match address {
+        BitcoinAddress::P2wpkh(hash) => {
+            let [signature, public_key] = witness.as_slice() else {
+                return Err(VerifySignatureError::InvalidBitcoinSignature(
+                    "a P2WPKH witness must have 2 items".to_string(),
+                ));
+            };
+            if hash160(public_key) != *hash {
+                return Err(VerifySignatureError::SignerAddressMismatch);
+            }
+            let (sighash_type, der_signature) = signature.split_last().ok_or_else(|| {
+                VerifySignatureError::InvalidBitcoinSignature("empty signature".to_string())
+            })?;
+            if *sighash_type != SIGHASH_ALL {
+                return Err(VerifySignatureError::InvalidBitcoinSignature(format!(
+                    "unsupported sighash type {}",
+                    sighash_type
+                )));
+            }
+            let public_key = PublicKey::parse_slice(public_key, None)
+                .map_err(|e| VerifySignatureError::InvalidBitcoinSignature(format!("{:?}", e)))?;
+            let mut signature = Signature::parse_der(der_signature)
+                .map_err(|e| VerifySignatureError::FailedParsingSignature(format!("{:?}", e)))?;
+            signature.normalize_s();
+            let txid = to_spend_txid(message, &[&[0x00, 0x14][..], &hash[..]].concat());
+            let sighash = Message::parse(&p2wpkh_sighash(&txid, hash));
+            if libsecp256k1::verify(&sighash, &signature, &public_key) {
+                Ok(())
+            } else {
+                Err(VerifySignatureError::SignerAddressMismatch)
+            }
+        }
+        BitcoinAddress::P2tr(output_key) => {
+            let [signature] = witness.as_slice() else {
+                return Err(VerifySignatureError::InvalidBitcoinSignature(
+                    "only the key path spending of P2TR is supported".to_string(),
+                ));
+            };
+            let sighash_type = match signature.len() {
+                64 => SIGHASH_DEFAULT,
+                65 if signature[64] == SIGHASH_ALL => SIGHASH_ALL,
+                _ => {
+                    return Err(VerifySignatureError::InvalidBitcoinSignature(
+                        "invalid Schnorr signature".to_string(),
+                    ))
+                }
+            };
+            let public_key = k256::schnorr::VerifyingKey::from_bytes(output_key)
+                .map_err(|e| VerifySignatureError::InvalidBitcoinSignature(format!("{:?}", e)))?;
+            let signature = k256::schnorr::Signature::try_from(&signature[..64])
+                .map_err(|e| VerifySignatureError::FailedParsingSignature(format!("{:?}", e)))?;
+            let script_pubkey = [&[0x51, 0x20][..], &output_key[..]].concat();
+            let txid = to_spend_txid(message, &script_pubkey);
+            let sighash = p2tr_sighash(&txid, &script_pubkey, sighash_type);
+            public_key
+                .verify_raw(&sighash, &signature)
+                .map_err(|_| VerifySignatureError::SignerAddressMismatch)
+        }
+        BitcoinAddress::P2pkh(_) | BitcoinAddress::P2sh(_) => {
+            Err(VerifySignatureError::InvalidBitcoinSignature(
+                "BIP-322 signatures are only supported for P2WPKH and P2TR addresses".to_string(),
+            ))
+        }
+    }
+}
+
+/// The id of the virtual `to_spend` transaction of BIP-322, in internal byte order.
+fn to_spend_txid(message: &[u8], script_pubkey: &[u8]) -> [u8; 32] {
+    let message_hash = tagged_hash(b"BIP0322-signed-message", message);
+    let mut tx = vec![];
+    tx.extend_from_slice(&0u32.to_le_bytes()); // version
+    tx.push(1); // number of inputs
+    tx.extend_from_slice(&[0; 32]); // previous txid
+    tx.extend_from_slice(&u32::MAX.to_le_bytes()); // previous vout
+    tx.extend_from_slice(&[34, 0x00, 0x20]); // script sig: OP_0 PUSH32 <message hash>
+    tx.extend_from_slice(&message_hash);
+    tx.extend_from_slice(&0u32.to_le_bytes()); // sequence
+    tx.push(1); // number of outputs
+    tx.extend_from_slice(&0u64.to_le_bytes()); // value
+    write_compact_size(&mut tx, script_pubkey.len());
+    tx.extend_from_slice(script_pubkey);
+    tx.extend_from_slice(&0u32.to_le_bytes()); // lock time
+    sha256d(&tx)
+}
+
+/// The BIP-143 signature hash of the input of the `to_sign` transaction, spending a P2WPKH
+/// output with `SIGHASH_ALL`.
+fn p2wpkh_sighash(txid: &[u8; 32], key_hash: &[u8; 20]) -> [u8; 32] {
+    let outpoint = [&txid[..], &0u32.to_le_bytes()].concat();
+    let mut preimage = vec![];
+    preimage.extend_from_slice(&0u32.to_le_bytes()); // version
+    preimage.extend_from_slice(&sha256d(&outpoint)); // hash of the outpoints
+    preimage.extend_from_slice(&sha256d(&0u32.to_le_bytes())); // hash of the sequences
+    preimage.extend_from_slice(&outpoint);
+    preimage.extend_from_slice(&[0x19, 0x76, 0xa9, 0x14]); // script code
+    preimage.extend_from_slice(key_hash);
+    preimage.extend_from_slice(&[0x88, 0xac]);
+    preimage.extend_from_slice(&0u64.to_le_bytes()); // amount
+    preimage.extend_from_slice(&0u32.to_le_bytes()); // sequence
+    preimage.extend_from_slice(&sha256d(&TO_SIGN_OUTPUT)); // hash of the outputs
+    preimage.extend_from_slice(&0u32.to_le_bytes()); // lock time
+    preimage.extend_from_slice(&u32::from(SIGHASH_ALL).to_le_bytes());
+    sha256d(&preimage)
+}
+
+/// The BIP-341 signature hash of the input of the `to_sign` transaction, spending a P2TR
+/// output by the key path.
+fn p2tr_sighash(txid: &[u8; 32], script_pubkey: &[u8], sighash_type: u8) -> [u8; 32] {
+    let outpoint = [&txid[..], &0u32.to_le_bytes()].concat();
+    let mut script_pubkeys = vec![];
+    write_compact_size(&mut script_pubkeys, script_pubkey.len());
+    script_pubkeys.extend_from_slice(script_pubkey);
+    let mut message = vec![0x00, sighash_type]; // epoch and sighash type
+    message.extend_from_slice(&0u32.to_le_bytes()); // version
+    message.extend_from_slice(&0u32.to_le_bytes()); // lock time
+    message.extend_from_slice(&Sha256::digest(outpoint)); // hash of the outpoints
+    message.extend_from_slice(&Sha256::digest(0u64.to_le_bytes())); // hash of the amounts
+    message.extend_from_slice(&Sha256::digest(script_pubkeys)); // hash of the script pubkeys
+    message.extend_from_slice(&Sha256::digest(0u32.to_le_bytes())); // hash of the sequences
+    message.extend_from_slice(&Sha256::digest(TO_SIGN_OUTPUT)); // hash of the outputs
+    message.push(0); // spend type: key path without annex
+    message.extend_from_slice(&0u32.to_le_bytes()); // input index
+    tagged_hash(b"TapSighash", &message)
+}
+
+/// Parse a witness stack in the consensus encoding, which must be consumed entirely.
+fn parse_witness(mut data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let count = read_compact_size(&mut data)?;
+    let mut items = vec![];
+    for _ in 0..count {
+        let len = read_compact_size(&mut data)?;
+        if data.len() < len {
+            return None;
+        }
+        let (item, rest) = data.split_at(len);
+        items.push(item.to_vec());
+        data = rest;
+    }
+    data.is_empty().then_some(items)
+}
+
+fn read_compact_size(data: &mut &[u8]) -> Option<usize> {
+    let (&first, rest) = data.split_first()?;
+    let (len, rest) = match first {
+        0xfd => (2, rest),
+        0xfe => (4, rest),
+        0xff => (8, rest),
+        _ => {
+            *data = rest;
+            return Some(first as usize);
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..len].copy_from_slice(&rest[..len]);
+    *data = &rest[len..];
+    usize::try_from(u64::from_le_bytes(bytes)).ok()
+}
+
+fn write_compact_size(data: &mut Vec<u8>, size: usize) {
+    match size {
+        0..=0xfc => data.push(size as u8),
+        0xfd..=0xffff => {
+            data.push(0xfd);
+            data.extend_from_slice(&(size as u16).to_le_bytes());
+        }
+        _ => {
+            data.push(0xfe);
+            data.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+    }
+}
+
+fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    ripemd::Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag);
+    Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    /// The addresses of the test vectors of BIP-322.
+    const P2WPKH_ADDRESS: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    const P2TR_ADDRESS: &str = "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3";
+
+    fn verify(address: &str, message: &str, signature: &str) -> Result<(), VerifySignatureError> {
+        let address = parse_address(address, Network::Mainnet).expect("invalid address");
+        verify_signature(
+            &address,
+            message.as_bytes(),
+            &STANDARD.decode(signature).unwrap(),
+        )
+    }
+
+    /// The id of a transaction, in the byte order shown by block explorers.
+    fn display_txid(txid: [u8; 32]) -> String {
+        let mut txid = txid;
+        txid.reverse();
+        hex::encode(txid)
+    }
+
+    #[test]
+    fn bip322_message_hashes() {
+        assert_eq!(
+            hex::encode(tagged_hash(b"BIP0322-signed-message", b"")),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            hex::encode(tagged_hash(b"BIP0322-signed-message", b"Hello World")),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn bip322_to_spend_txids() {
+        let Some(BitcoinAddress::P2wpkh(hash)) = parse_address(P2WPKH_ADDRESS, Network::Mainnet)
+        else {
+            panic!("not a P2WPKH address");
+        };
+        let script_pubkey = [&[0x00, 0x14][..], &hash[..]].concat();
+        assert_eq!(
+            display_txid(to_spend_txid(b"", &script_pubkey)),
+            "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7"
+        );
+        assert_eq!(
+            display_txid(to_spend_txid(b"Hello World", &script_pubkey)),
+            "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b"
+        );
+    }
+
+    #[test]
+    fn bip322_p2wpkh_vectors() {
+        let empty_message_signature = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        let hello_world_signature = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        assert!(verify(P2WPKH_ADDRESS, "", empty_message_signature).is_ok());
+        assert!(verify(P2WPKH_ADDRESS, "Hello World", hello_world_signature).is_ok());
+        assert!(verify(P2WPKH_ADDRESS, "Hello World", empty_message_signature).is_err());
+        assert!(verify(P2WPKH_ADDRESS, "", hello_world_signature).is_err());
+    }
+
+    #[test]
+    fn bip322_p2tr_vector() {
+        let signature = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        assert!(verify(P2TR_ADDRESS, "Hello World", signature).is_ok());
+        assert!(verify(P2TR_ADDRESS, "Hello World!", signature).is_err());
+    }
+
+    /// The signed message example of bitcoinjs-message.
+    #[test]
+    fn bip137_vector() {
+        let address = "1F3sAm6ZtwLAUnj7d38pGFxtP3RVEvtsbV";
+        let message = "This is an example of a signed message.";
+        let signature =
+            "H9L5yLFjti0QTHhPyFrZCT1V/MMnBtXKmoiKDZ78NDBjERki6ZTQZdSMCtkgoNmp17By9ItJr8o7ChX0XxY91nk=";
+        assert!(verify(address, message, signature).is_ok());
+        assert!(verify(address, "This is another message.", signature).is_err());
+    }
+
+    #[test]
+    fn addresses_of_other_networks_are_rejected() {
+        for address in [
+            P2WPKH_ADDRESS,
+            P2TR_ADDRESS,
+            "1F3sAm6ZtwLAUnj7d38pGFxtP3RVEvtsbV",
+        ] {
+            assert!(parse_address(address, Network::Mainnet).is_some());
+            assert!(parse_address(address, Network::Testnet).is_none());
+            assert!(parse_address(address, Network::Regtest).is_none());
+        }
+        let testnet_address = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        assert!(parse_address(testnet_address, Network::Testnet).is_some());
+        assert!(parse_address(testnet_address, Network::Mainnet).is_none());
+        assert!(parse_address(testnet_address, Network::Regtest).is_none());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::state::BtcStakingPoolState;
+use core::cell::RefCell;
+use ic_certified_map::{
+    fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree,
+};
+use serde::Serialize;
+
+/// The certified values of a staker, or of the pool, by name.
+type Values = RbTree<&'static str, Vec<u8>>;
+
+/// The tree of all the certified data of the canister.
+///
+/// ```text
+/// root
+/// ├── http_assets: the SHA-256 hashes of the certified HTTP responses, by path
+/// ├── pool: total_ckbtc_in_pool, treasury_balance
+/// └── stakers: ckbtc_balance, otbtc_balance, by Ethereum address
+/// ```
+///
+/// The values are encoded as 8 byte big-endian integers.
+struct CertifiedTree {
+    http_assets: RbTree<String, Hash>,
+    pool: Values,
+    stakers: RbTree<String, Values>,
+}
+
+thread_local! {
+    static __TREE: RefCell<CertifiedTree> = RefCell::new(CertifiedTree {
+        http_assets: RbTree::new(),
+        pool: RbTree::new(),
+        stakers: RbTree::new(),
+    });
+}
+
+impl CertifiedTree {
+    fn root_hash(&self) -> Hash {
+        fork_hash(
+            &fork_hash(
+                &labeled_hash(b"http_assets", &self.http_assets.root_hash()),
+                &labeled_hash(b"pool", &self.pool.root_hash()),
+            ),
+            &labeled_hash(b"stakers", &self.stakers.root_hash()),
+        )
+    }
+}
+
+fn pool_values(state: &BtcStakingPoolState) -> Values {
+    let mut values = RbTree::new();
+    values.insert(
+        "total_ckbtc_in_pool",
+        state.total_ckbtc_in_pool.to_be_bytes().to_vec(),
+    );
+    values.insert(
+        "treasury_balance",
+        state.treasury_balance.to_be_bytes().to_vec(),
+    );
+    values
+}
+
+fn staker_values(state: &BtcStakingPoolState, eth_address: &str) -> Option<Values> {
+    state.stakers_map.get(eth_address).map(|staker| {
+        let mut values = RbTree::new();
+        values.insert("ckbtc_balance", staker.ckbtc_balance.to_be_bytes().to_vec());
+        values.insert("otbtc_balance", staker.otbtc_balance.to_be_bytes().to_vec());
+        values
+    })
+}
+
+fn certify(tree: &CertifiedTree) {
+    // There is no certified data outside of a canister, such as in the unit tests.
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::api::set_certified_data(&tree.root_hash());
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = tree;
+}
+
+/// Rebuild the certified tree of the pool and of all the stakers.
+pub fn certify_all(state: &BtcStakingPoolState) {
+    __TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.pool = pool_values(state);
+        tree.stakers = RbTree::new();
+        for eth_address in state.stakers_map.keys() {
+            if let Some(values) = staker_values(state, eth_address) {
+                tree.stakers.insert(eth_address.clone(), values);
+            }
+        }
+        certify(&tree);
+    });
+}
+
+/// Update the certified values of the pool.
+///
+/// Must be called after every change of `total_ckbtc_in_pool` or `treasury_balance`.
+pub fn certify_pool(state: &BtcStakingPoolState) {
+    __TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.pool = pool_values(state);
+        certify(&tree);
+    });
+}
+
+/// Update the certified values of a staker and of the pool.
+///
+/// Must be called after every change of the balances of a staker, and after a staker is created.
+/// Also marks the JSON response of the staker to be refreshed.
+pub fn certify_staker(state: &BtcStakingPoolState, eth_address: &str) {
+    crate::http::staker_changed(eth_address);
+    __TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.pool = pool_values(state);
+        match staker_values(state, eth_address) {
+            Some(values) => tree.stakers.insert(eth_address.to_string(), values),
+            None => tree.stakers.delete(eth_address.as_bytes()),
+        }
+        certify(&tree);
+    });
+}
+
+/// Update the hashes of the certified HTTP responses, by path, removing the paths without one.
+pub fn update_http_assets(http_assets: Vec<(String, Option<Hash>)>) {
+    __TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for (path, hash) in http_assets {
+            match hash {
+                Some(hash) => tree.http_assets.insert(path, hash),
+                None => tree.http_assets.delete(path.as_bytes()),
+            }
+        }
+        certify(&tree);
+    });
+}
+
+/// The CBOR encoding of a hash tree, tagged as self-describing.
+fn encode_tree(tree: HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer
+        .self_describe()
+        .expect("failed to encode the hash tree, should not happen");
+    tree.serialize(&mut serializer)
+        .expect("failed to encode the hash tree, should not happen");
+    serializer.into_inner()
+}
+
+/// The witness of the certified HTTP response at a path, or of its absence.
+pub fn http_asset_witness(path: &str) -> Vec<u8> {
+    __TREE.with(|tree| {
+        let tree = tree.borrow();
+        encode_tree(fork(
+            fork(
+                labeled(b"http_assets", tree.http_assets.witness(path.as_bytes())),
+                HashTree::Pruned(labeled_hash(b"pool", &tree.pool.root_hash())),
+            ),
+            HashTree::Pruned(labeled_hash(b"stakers", &tree.stakers.root_hash())),
+        ))
+    })
+}
+
+/// The witness of the certified values of the pool and of a staker, or of its absence.
+pub fn staker_witness(eth_address: &str) -> Vec<u8> {
+    __TREE.with(|tree| {
+        let tree = tree.borrow();
+        encode_tree(fork(
+            fork(
+                HashTree::Pruned(labeled_hash(b"http_assets", &tree.http_assets.root_hash())),
+                labeled(b"pool", tree.pool.as_hash_tree()),
+            ),
+            labeled(b"stakers", tree.stakers.witness(eth_address.as_bytes())),
+        ))
+    })
+}
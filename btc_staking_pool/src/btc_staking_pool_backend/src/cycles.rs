@@ -0,0 +1,34 @@
+use crate::{log, state};
+
+/// Check the cycles balance of the canister against the low-water mark, and log when the
+/// balance crosses it.
+///
+/// Returns whether the balance is below the low-water mark, in which case the non-essential
+/// work of the canister should be skipped.
+pub fn check_cycles_balance() -> bool {
+    let balance = ic_cdk::api::canister_balance128();
+    state::mutate_state(|state| {
+        let low = balance < state.cycles_low_water_mark;
+        if low != state.cycles_low {
+            state.cycles_low = low;
+            if low {
+                log::error(
+                    "cycles",
+                    format!(
+                        "the cycles balance {} is below the low-water mark {}, pausing the non-essential work",
+                        balance, state.cycles_low_water_mark
+                    ),
+                );
+            } else {
+                log::info(
+                    "cycles",
+                    format!(
+                        "the cycles balance {} is back above the low-water mark {}, resuming the non-essential work",
+                        balance, state.cycles_low_water_mark
+                    ),
+                );
+            }
+        }
+        low
+    })
+}
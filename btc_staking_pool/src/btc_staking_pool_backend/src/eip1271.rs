@@ -0,0 +1,100 @@
+use crate::errors::VerifySignatureError;
+use crate::state::Eip1271Config;
+use candid::{CandidType, Deserialize, Reserved};
+use serde_json::{json, Value};
+
+/// The selector of `isValidSignature(bytes32,bytes)`, which is also the value returned for a
+/// valid signature.
+const MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+/// The RPC service of a request to the EVM RPC canister, of which only the chain id is used.
+#[derive(CandidType)]
+enum RpcService {
+    Chain(u64),
+}
+
+/// The result of a request to the EVM RPC canister. The error is only logged, so its details
+/// are not decoded.
+#[derive(CandidType, Deserialize)]
+enum RequestResult {
+    Ok(String),
+    Err(Reserved),
+}
+
+/// Verify the signature of a message hash by a smart contract wallet, by calling
+/// `isValidSignature` on the contract at the Ethereum address through the EVM RPC canister.
+pub async fn verify_signature(
+    config: &Eip1271Config,
+    eth_address: &str,
+    message_hash: [u8; 32],
+    signature: &[u8],
+) -> Result<(), VerifySignatureError> {
+    let data = is_valid_signature_call(message_hash, signature);
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_call",
+        "params": [
+            {
+                "to": format!("0x{}", eth_address),
+                "data": format!("0x{}", hex::encode(data)),
+            },
+            "latest",
+        ],
+    });
+    let (result,): (RequestResult,) = ic_cdk::api::call::call_with_payment128(
+        config.evm_rpc_canister,
+        "request",
+        (
+            RpcService::Chain(config.chain_id),
+            request.to_string(),
+            config.max_response_bytes,
+        ),
+        config.cycles,
+    )
+    .await
+    .map_err(|e| {
+        VerifySignatureError::ContractCallFailed(format!(
+            "failed to call the EVM RPC canister: {:?}",
+            e
+        ))
+    })?;
+    let response = match result {
+        RequestResult::Ok(response) => response,
+        RequestResult::Err(_) => {
+            return Err(VerifySignatureError::ContractCallFailed(
+                "the EVM RPC canister rejected the request".to_string(),
+            ))
+        }
+    };
+    let returned = serde_json::from_str::<Value>(&response)
+        .ok()
+        .and_then(|response| response["result"].as_str().map(str::to_string))
+        .and_then(|result| hex::decode(result.trim_start_matches("0x")).ok())
+        .ok_or_else(|| {
+            VerifySignatureError::ContractCallFailed(format!("unexpected response: {}", response))
+        })?;
+    if returned.len() == 32 && returned[..4] == MAGIC_VALUE {
+        Ok(())
+    } else {
+        Err(VerifySignatureError::InvalidContractSignature)
+    }
+}
+
+/// The ABI encoding of the call `isValidSignature(message_hash, signature)`.
+fn is_valid_signature_call(message_hash: [u8; 32], signature: &[u8]) -> Vec<u8> {
+    let mut data = MAGIC_VALUE.to_vec();
+    data.extend_from_slice(&message_hash);
+    // The offset of the dynamic `bytes` argument, after the two head words.
+    data.extend_from_slice(&abi_word(64));
+    data.extend_from_slice(&abi_word(signature.len() as u64));
+    data.extend_from_slice(signature);
+    data.resize(data.len() + (32 - signature.len() % 32) % 32, 0);
+    data
+}
+
+fn abi_word(value: u64) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
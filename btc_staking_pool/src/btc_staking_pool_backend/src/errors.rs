@@ -1,31 +1,56 @@
+use crate::state::OperationError;
 use candid::CandidType;
 
 #[derive(CandidType, Debug)]
 pub enum GetBtcDepositAddressError {
-    /// The specified address is not a valid Ethereum address.
+    /// The specified address is not a valid Ethereum or Bitcoin address.
     InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
     /// The call to the CKBTC minter canister failed.
     CkbtcMinterError(String),
+    /// The call to the ckBTC or otBTC ledger canister failed.
+    LedgerError(String),
 }
 
 #[derive(CandidType, Debug)]
 pub enum UpdateBalanceError {
-    /// The specified address is not a valid Ethereum address.
+    /// The specified address is not a valid Ethereum or Bitcoin address.
     InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
     /// The call to the CKBTC minter canister failed.
     CkbtcMinterError(String),
+    /// The call to the ckBTC ledger canister failed.
+    CkbtcLedgerError(String),
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
 }
 
 #[derive(CandidType, Debug)]
 pub enum StakeError {
-    /// The specified address is not a valid Ethereum address.
+    /// The specified address is not a valid Ethereum or Bitcoin address.
     InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// The specified amount is lower than the minimum amount.
+    AmountTooLow { min: u64 },
     /// Staker record not found.
     LackOfStakerRecord,
     /// The specified amount is larger than the available balance.
     NotEnoughCkbtcBalance,
+    /// The Ethereum address is not in the staker allowlist.
+    NotInAllowlist,
+    /// The specified amount exceeds the cap of the pool.
+    ExceedsPoolCap { remaining: u64 },
+    /// The specified amount exceeds the cap of the staker.
+    ExceedsStakerCap { remaining: u64 },
     /// The signature is invalid.
     InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
     /// The call to the ckBTC ledger canister failed.
     CkbtcLedgerError(String),
     /// The transfer on the ckBTC ledger canister failed.
@@ -38,14 +63,22 @@ pub enum StakeError {
 
 #[derive(CandidType, Debug)]
 pub enum UnstakeError {
-    /// The specified address is not a valid Ethereum address.
+    /// The specified address is not a valid Ethereum or Bitcoin address.
     InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// The specified amount is lower than the minimum amount.
+    AmountTooLow { min: u64 },
     /// Staker record not found.
     LackOfStakerRecord,
     /// The specified amount is larger than the available balance.
     NotEnoughOtbtcBalance,
     /// The signature is invalid.
     InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
     /// The call to the otBTC ledger canister failed.
     OtbtcLedgerError(String),
     /// The transfer on the otBTC ledger canister failed.
@@ -58,6 +91,10 @@ pub enum UnlockTokensInQueueError {
     LackOfStakerRecord,
     /// The withdrawal time has not been reached yet.
     UnlockTimeNotReached,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
     /// The call to the ckBTC ledger canister failed.
     CkbtcLedgerError(String),
     /// The transfer on the ckBTC ledger canister failed.
@@ -66,20 +103,211 @@ pub enum UnlockTokensInQueueError {
 
 #[derive(CandidType, Debug)]
 pub enum WithdrawBtcError {
-    /// The specified address is not a valid Ethereum address.
+    /// The specified address is not a valid Ethereum or Bitcoin address.
     InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// The specified amount is lower than the minimum amount.
+    AmountTooLow { min: u64 },
     /// Staker record not found.
     LackOfStakerRecord,
     /// The specified amount is larger than the available balance.
     NotEnoughCkbtcBalance,
     /// The signature is invalid.
     InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
     /// The call to the ckBTC ledger canister failed.
     CkbtcLedgerError(String),
     /// The transfer on the ckBTC ledger canister failed.
     CkbtcTransferError(String),
 }
 
+#[derive(CandidType, Debug)]
+pub enum TransferOtbtcError {
+    /// The specified address of the sender is not a valid Ethereum or Bitcoin address.
+    InvalidEthereumAddress,
+    /// The specified address of the recipient is not a valid staker address, or is the sender.
+    InvalidRecipient,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// Staker record not found.
+    LackOfStakerRecord,
+    /// The specified amount is not larger than the otBTC transfer fee.
+    AmountTooLow { min: u64 },
+    /// The specified amount and the fee are larger than the available balance.
+    NotEnoughOtbtcBalance,
+    /// The signature is invalid.
+    InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
+    /// The call to the otBTC ledger canister failed.
+    OtbtcLedgerError(String),
+    /// The transfer on the otBTC ledger canister failed.
+    OtbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum ExportOtbtcError {
+    /// The specified address is not a valid Ethereum or Bitcoin address.
+    InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// Staker record not found.
+    LackOfStakerRecord,
+    /// The specified amount is not larger than the otBTC transfer fee.
+    AmountTooLow { min: u64 },
+    /// The specified amount and the fee are larger than the available balance.
+    NotEnoughOtbtcBalance,
+    /// The signature is invalid.
+    InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
+    /// The call to the otBTC ledger canister failed.
+    OtbtcLedgerError(String),
+    /// The transfer on the otBTC ledger canister failed.
+    OtbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum ImportOtbtcError {
+    /// The specified address is not a valid Ethereum or Bitcoin address.
+    InvalidEthereumAddress,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The call to the otBTC ledger canister failed.
+    OtbtcLedgerError(String),
+    /// The transfer on the otBTC ledger canister failed.
+    OtbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum DepositCkbtcError {
+    /// The specified address is not a valid Ethereum or Bitcoin address.
+    InvalidEthereumAddress,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The call to the ckBTC ledger canister failed.
+    CkbtcLedgerError(String),
+    /// The transfer on the ckBTC ledger canister failed.
+    CkbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum WithdrawCkbtcError {
+    /// The specified address is not a valid Ethereum or Bitcoin address.
+    InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// Staker record not found.
+    LackOfStakerRecord,
+    /// The specified amount is not larger than the ckBTC transfer fee.
+    AmountTooLow { min: u64 },
+    /// The specified amount and the fees are larger than the available balance.
+    NotEnoughCkbtcBalance,
+    /// The signature is invalid.
+    InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
+    /// The call to the ckBTC ledger canister failed.
+    CkbtcLedgerError(String),
+    /// The transfer on the ckBTC ledger canister failed.
+    CkbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum AttestationError {
+    /// The specified address is not a valid Ethereum address.
+    InvalidEthereumAddress,
+    /// The caller neither acts for the Ethereum address nor is a controller of the canister.
+    NotAuthorized,
+    /// Staker record not found.
+    LackOfStakerRecord,
+    /// The attestations or the threshold ECDSA key signing Ethereum messages is not configured.
+    NotConfigured,
+    /// The cycles balance of the canister is below its low-water mark.
+    CyclesBalanceLow,
+    /// The call to the management canister failed.
+    SigningFailed(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum BridgeOtbtcError {
+    /// The specified address is not a valid Ethereum address.
+    InvalidEthereumAddress,
+    /// The anonymous principal cannot have a staker account.
+    AnonymousCaller,
+    /// The bridge or the threshold ECDSA key signing Ethereum messages is not configured.
+    NotConfigured,
+    /// Staker record not found.
+    LackOfStakerRecord,
+    /// The specified amount is not larger than the otBTC transfer fee.
+    AmountTooLow { min: u64 },
+    /// The specified amount and the fee are larger than the available balance.
+    NotEnoughOtbtcBalance,
+    /// The signature is invalid.
+    InvalidSignature,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed or compensated later.
+    OperationPending { operation_id: u64, reason: String },
+    /// The otBTC is locked, but the voucher could not be signed yet, see `get_bridge_voucher`.
+    VoucherNotSigned { operation_id: u64, reason: String },
+    /// The call to the otBTC ledger canister failed.
+    OtbtcLedgerError(String),
+    /// The transfer on the otBTC ledger canister failed.
+    OtbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum BridgeVoucherError {
+    /// No voucher has the specified nonce.
+    VoucherNotFound,
+    /// The call to the management canister failed.
+    SigningFailed(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum RedeemFromEthError {
+    /// The bridge is not configured.
+    NotConfigured,
+    /// The caller is not the operator of the bridge.
+    NotOperator,
+    /// The specified address is not a valid Ethereum address.
+    InvalidEthereumAddress,
+    /// The burn has been redeemed already.
+    AlreadyRedeemed,
+    /// The burnt amount is larger than the otBTC locked in the bridge.
+    ExceedsBridgedAmount,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+    /// The operation is partially done, and will be completed later.
+    OperationPending { operation_id: u64, reason: String },
+    /// The otBTC ledger rejected the unlock, which is left to the controllers, see
+    /// `resolve_operation`.
+    UnlockFailed { operation_id: u64, reason: String },
+}
+
+#[derive(CandidType, Debug)]
+pub enum ResolveOperationError {
+    /// The caller is not a controller of the canister.
+    NotController,
+    /// No operation has the specified id.
+    OperationNotFound,
+    /// The operation is not left to the controllers.
+    NotLeftToOperator,
+    /// The staker is locked by an unfinished operation.
+    StakerBusy,
+}
+
 #[derive(CandidType, Debug)]
 pub enum VerifySignatureError {
     InvalidSignatureLength,
@@ -88,4 +316,181 @@ pub enum VerifySignatureError {
     FailedParsingSignature(String),
     FailedRecoveringPublicKey(String),
     SignerAddressMismatch,
+    /// The signature is not a valid BIP-137 or BIP-322 signature for the Bitcoin address.
+    InvalidBitcoinSignature(String),
+    /// The smart contract wallet could not be asked to check the signature.
+    ContractCallFailed(String),
+    /// The smart contract wallet rejected the signature.
+    InvalidContractSignature,
+}
+
+#[derive(CandidType, Debug)]
+pub enum SiweError {
+    /// Sign-In with Ethereum is not configured.
+    NotConfigured,
+    /// The anonymous principal cannot open a session.
+    AnonymousCaller,
+    /// The specified address is not a valid Ethereum address.
+    InvalidEthereumAddress,
+    /// No sign-in was prepared for the caller.
+    NoPendingLogin,
+    /// The sign-in message was not signed in time.
+    LoginExpired,
+    /// The signature of the sign-in message is invalid.
+    InvalidSignature(VerifySignatureError),
+    /// The caller has no active session.
+    NoSession,
+}
+
+#[derive(CandidType, Debug)]
+pub enum ConfigError {
+    /// The caller is not a controller of the canister.
+    NotController,
+    /// The specified configuration is invalid.
+    InvalidConfig(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum DistributeRewardsError {
+    /// The caller is not a controller of the canister.
+    NotController,
+    /// A stake or unstake operation is unfinished, or left to the controllers, so the ckBTC in
+    /// the main account is not settled.
+    OperationsInProgress,
+    /// The call to the ckBTC ledger canister failed.
+    CkbtcLedgerError(String),
+    /// The transfer on the ckBTC ledger canister failed.
+    CkbtcTransferError(String),
+}
+
+#[derive(CandidType, Debug)]
+pub enum WithdrawTreasuryError {
+    /// The caller is not the treasury owner.
+    NotTreasuryOwner,
+    /// The specified amount and the fee are larger than the treasury balance.
+    NotEnoughTreasuryBalance,
+    /// The transfer on the ckBTC ledger canister failed, and the amount is released.
+    CkbtcTransferError(String),
+    /// The outcome of the transfer is unknown, so the amount stays reserved and the transfer
+    /// with the given nonce is retried until the ckBTC ledger answers.
+    TransferPending { nonce: u64, reason: String },
+}
+
+#[derive(CandidType, Debug)]
+pub enum SyncMinterInfoError {
+    /// The caller is not a controller of the canister.
+    NotController,
+    /// The call to the CKBTC minter canister failed.
+    CkbtcMinterError(String),
+    /// The call to the ckBTC or otBTC ledger canister failed.
+    LedgerError(String),
+}
+
+impl From<OperationError> for StakeError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) => StakeError::CkbtcLedgerError(e),
+            OperationError::CkbtcTransferError(e) => StakeError::CkbtcTransferError(e),
+            OperationError::OtbtcLedgerError(e) => StakeError::OtbtcLedgerError(e),
+            OperationError::OtbtcTransferError(e) => StakeError::OtbtcTransferError(e),
+        }
+    }
+}
+
+/// Unstaking only transfers on the otBTC ledger.
+impl From<OperationError> for UnstakeError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                UnstakeError::OtbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                UnstakeError::OtbtcTransferError(e)
+            }
+        }
+    }
+}
+
+/// Unlocking only transfers on the ckBTC ledger.
+impl From<OperationError> for UnlockTokensInQueueError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                UnlockTokensInQueueError::CkbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                UnlockTokensInQueueError::CkbtcTransferError(e)
+            }
+        }
+    }
+}
+
+/// Transferring otBTC only transfers on the otBTC ledger.
+impl From<OperationError> for TransferOtbtcError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                TransferOtbtcError::OtbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                TransferOtbtcError::OtbtcTransferError(e)
+            }
+        }
+    }
+}
+
+/// Exporting otBTC only transfers on the otBTC ledger.
+impl From<OperationError> for ExportOtbtcError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                ExportOtbtcError::OtbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                ExportOtbtcError::OtbtcTransferError(e)
+            }
+        }
+    }
+}
+
+/// Withdrawing only transfers on the ckBTC ledger.
+impl From<OperationError> for WithdrawBtcError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                WithdrawBtcError::CkbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                WithdrawBtcError::CkbtcTransferError(e)
+            }
+        }
+    }
+}
+
+/// Bridging otBTC only transfers on the otBTC ledger.
+impl From<OperationError> for BridgeOtbtcError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                BridgeOtbtcError::OtbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                BridgeOtbtcError::OtbtcTransferError(e)
+            }
+        }
+    }
+}
+
+/// Withdrawing only transfers on the ckBTC ledger.
+impl From<OperationError> for WithdrawCkbtcError {
+    fn from(error: OperationError) -> Self {
+        match error {
+            OperationError::CkbtcLedgerError(e) | OperationError::OtbtcLedgerError(e) => {
+                WithdrawCkbtcError::CkbtcLedgerError(e)
+            }
+            OperationError::CkbtcTransferError(e) | OperationError::OtbtcTransferError(e) => {
+                WithdrawCkbtcError::CkbtcTransferError(e)
+            }
+        }
+    }
 }
@@ -0,0 +1,214 @@
+use crate::keccak256;
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use libsecp256k1::{Message, PublicKey, RecoveryId, Signature};
+
+/// The derivation path of the threshold ECDSA key of the pool which signs Ethereum messages.
+const DERIVATION_PATH: &[u8] = b"eth_signer";
+
+fn key_id(key_name: &str) -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: key_name.to_string(),
+    }
+}
+
+/// Fetch the SEC1 encoded public key of the pool which signs Ethereum messages.
+pub async fn fetch_public_key(key_name: &str) -> Result<Vec<u8>, String> {
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id: key_id(key_name),
+    })
+    .await
+    .map_err(|e| format!("failed to call management canister: {:?}", e))?;
+    Ok(response.public_key)
+}
+
+/// The Ethereum address of a SEC1 encoded public key.
+pub fn eth_address(public_key: &[u8]) -> Result<[u8; 20], String> {
+    let public_key = PublicKey::parse_slice(public_key, None)
+        .map_err(|e| format!("invalid ECDSA public key: {:?}", e))?;
+    let hash = keccak256(&public_key.serialize()[1..]);
+    Ok(hash[12..].try_into().expect("20 bytes, should not happen"))
+}
+
+/// Sign a message with the threshold ECDSA key of the pool, in the format checked by
+/// `ecrecover(toEthSignedMessageHash(keccak256(message)), v, r, s)` on Ethereum.
+///
+/// Returns the 65 bytes `r || s || v`, see `sign_digest`.
+pub async fn sign(key_name: &str, public_key: &[u8], message: &[u8]) -> Result<Vec<u8>, String> {
+    sign_digest(key_name, public_key, eth_signed_message_hash(message)).await
+}
+
+/// The hash `toEthSignedMessageHash(keccak256(message))` signed by `personal_sign`.
+fn eth_signed_message_hash(message: &[u8]) -> [u8; 32] {
+    let prefixed = [
+        &b"\x19Ethereum Signed Message:\n32"[..],
+        &keccak256(message)[..],
+    ]
+    .concat();
+    keccak256(&prefixed)
+}
+
+/// Sign a hash with the threshold ECDSA key of the pool, in the format checked by
+/// `ecrecover(digest, v, r, s)` on Ethereum.
+///
+/// Returns the 65 bytes `r || s || v`, with `v` being 27 or 28 and `s` in the lower half of the
+/// curve order, as required by most contracts.
+pub async fn sign_digest(
+    key_name: &str,
+    public_key: &[u8],
+    digest: [u8; 32],
+) -> Result<Vec<u8>, String> {
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: digest.to_vec(),
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id: key_id(key_name),
+    })
+    .await
+    .map_err(|e| format!("failed to call management canister: {:?}", e))?;
+    to_eth_signature(&digest, &response.signature, public_key)
+}
+
+/// Turn the 64 bytes `r || s` signature of a hash by a public key into the Ethereum signature
+/// `r || s || v`.
+fn to_eth_signature(
+    digest: &[u8; 32],
+    signature: &[u8],
+    public_key: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut signature = Signature::parse_standard_slice(signature)
+        .map_err(|e| format!("invalid ECDSA signature: {:?}", e))?;
+    signature.normalize_s();
+    let public_key = PublicKey::parse_slice(public_key, None)
+        .map_err(|e| format!("invalid ECDSA public key: {:?}", e))?;
+    // The management canister does not return the recovery id, so find the one recovering the
+    // public key of the pool.
+    let message = Message::parse(digest);
+    let recovery_id = (0..2)
+        .find(|&id| {
+            RecoveryId::parse(id)
+                .and_then(|recid| libsecp256k1::recover(&message, &signature, &recid))
+                .is_ok_and(|recovered| recovered == public_key)
+        })
+        .ok_or_else(|| "no recovery id recovers the public key, should not happen".to_string())?;
+    let mut signature = signature.serialize().to_vec();
+    signature.push(27 + recovery_id);
+    Ok(signature)
+}
+
+/// The EIP-712 domain separator of `EIP712Domain(string name,string version,uint256 chainId,
+/// address verifyingContract)`.
+pub fn eip712_domain_separator(
+    name: &str,
+    version: &str,
+    chain_id: u64,
+    verifying_contract: &[u8; 20],
+) -> [u8; 32] {
+    let type_hash = keccak256(
+        b"EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)",
+    );
+    keccak256(
+        &[
+            type_hash,
+            keccak256(name.as_bytes()),
+            keccak256(version.as_bytes()),
+            abi_uint(chain_id),
+            abi_address(verifying_contract),
+        ]
+        .concat(),
+    )
+}
+
+/// The EIP-712 hash `keccak256("\x19\x01" || domain_separator || struct_hash)` of a typed
+/// structured data, which is signed as is.
+pub fn eip712_digest(domain_separator: &[u8; 32], struct_hash: &[u8; 32]) -> [u8; 32] {
+    keccak256(&[&b"\x19\x01"[..], domain_separator, struct_hash].concat())
+}
+
+/// The ABI encoding of an `address`.
+pub fn abi_address(address: &[u8; 20]) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[12..].copy_from_slice(address);
+    word
+}
+
+/// The ABI encoding of a `uint256`.
+pub fn abi_uint(value: u64) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsecp256k1::SecretKey;
+
+    /// The local stand-in of the threshold ECDSA key, with the private key 1.
+    fn test_key() -> SecretKey {
+        let mut key = [0; 32];
+        key[31] = 1;
+        SecretKey::parse(&key).unwrap()
+    }
+
+    fn recover_address(digest: &[u8; 32], signature: &[u8]) -> [u8; 20] {
+        let recovery_id = RecoveryId::parse(signature[64] - 27).unwrap();
+        let signature = Signature::parse_standard_slice(&signature[..64]).unwrap();
+        let public_key =
+            libsecp256k1::recover(&Message::parse(digest), &signature, &recovery_id).unwrap();
+        eth_address(&public_key.serialize()).unwrap()
+    }
+
+    #[test]
+    fn eth_address_of_known_key() {
+        let public_key = PublicKey::from_secret_key(&test_key());
+        assert_eq!(
+            hex::encode(eth_address(&public_key.serialize()).unwrap()),
+            "7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+    }
+
+    #[test]
+    fn signature_recovers_signer_address() {
+        let public_key = PublicKey::from_secret_key(&test_key()).serialize();
+        let digest = eth_signed_message_hash(b"message");
+        let (signature, _) = libsecp256k1::sign(&Message::parse(&digest), &test_key());
+        // The management canister may return either of the two valid values of `s`.
+        let mut high_s = signature;
+        high_s.s = -high_s.s;
+        for signature in [signature, high_s] {
+            let signature = to_eth_signature(&digest, &signature.serialize(), &public_key).unwrap();
+            assert_eq!(signature.len(), 65);
+            assert!(signature[64] == 27 || signature[64] == 28);
+            assert!(!Signature::parse_standard_slice(&signature[..64])
+                .unwrap()
+                .s
+                .is_high());
+            assert_eq!(
+                recover_address(&digest, &signature),
+                eth_address(&public_key).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn eip712_domain_separator_of_specification_example() {
+        let verifying_contract: [u8; 20] = hex::decode("cccccccccccccccccccccccccccccccccccccccc")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            hex::encode(eip712_domain_separator(
+                "Ether Mail",
+                "1",
+                1,
+                &verifying_contract
+            )),
+            "f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    certification,
+    log::{self, LogLevel},
+    metrics,
+    state::{self, BtcStakingPoolState, UnstakeRequest},
+    types::{HttpRequest, HttpResponse},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use core::{cell::RefCell, time::Duration};
+use ic_certified_map::Hash;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The interval of refreshing the certified responses of the JSON API.
+const CERTIFICATION_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_QUEUE_PAGE_SIZE: usize = 100;
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+thread_local! {
+    /// The certified responses of the JSON API, by path.
+    static __CERTIFIED_RESPONSES: RefCell<BTreeMap<String, Vec<u8>>> = RefCell::default();
+    /// The stakers whose responses changed since the last refresh.
+    static __CHANGED_STAKERS: RefCell<BTreeSet<String>> = RefCell::default();
+}
+
+#[derive(Serialize)]
+struct PoolInfo {
+    total_ckbtc_in_pool: u64,
+    stakers: usize,
+    unstaking_queue_length: usize,
+    unbonding_period: u64,
+    treasury_balance: u64,
+}
+
+#[derive(Serialize)]
+struct StakerInfo<'a> {
+    eth_address: &'a str,
+    tx_nonce: u64,
+    ckbtc_balance: u64,
+    otbtc_balance: u64,
+    btc_deposit_address: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct QueuePage<'a> {
+    total: usize,
+    offset: usize,
+    requests: Vec<&'a UnstakeRequest>,
+}
+
+/// Certify the responses of the JSON API, and start the timer which refreshes them.
+pub fn start_timer() {
+    state::read_state(|state| {
+        for eth_address in state.stakers_map.keys() {
+            staker_changed(eth_address);
+        }
+    });
+    certify_responses();
+    ic_cdk_timers::set_timer_interval(CERTIFICATION_INTERVAL, || {
+        // The refresh is not essential, so it waits until the canister is topped up.
+        if !crate::cycles::check_cycles_balance() {
+            certify_responses();
+        }
+    });
+}
+
+/// Mark the response of a staker to be refreshed.
+///
+/// Must be called after every change of a staker record which is part of its response.
+pub fn staker_changed(eth_address: &str) {
+    __CHANGED_STAKERS.with(|changed| changed.borrow_mut().insert(eth_address.to_string()));
+}
+
+fn pool_json(state: &BtcStakingPoolState) -> Vec<u8> {
+    to_json(&PoolInfo {
+        total_ckbtc_in_pool: state.total_ckbtc_in_pool,
+        stakers: state.stakers_map.len(),
+        unstaking_queue_length: state.unstaking_queue.len(),
+        unbonding_period: state.unbonding_period,
+        treasury_balance: state.treasury_balance,
+    })
+}
+
+fn staker_json(state: &BtcStakingPoolState, eth_address: &str) -> Option<Vec<u8>> {
+    state.stakers_map.get(eth_address).map(|staker| {
+        to_json(&StakerInfo {
+            eth_address: &staker.eth_address,
+            tx_nonce: staker.tx_nonce,
+            ckbtc_balance: staker.ckbtc_balance,
+            otbtc_balance: staker.otbtc_balance,
+            btc_deposit_address: staker.btc_deposit_address.as_deref(),
+        })
+    })
+}
+
+fn queue_json(state: &BtcStakingPoolState, offset: usize, limit: usize) -> Vec<u8> {
+    to_json(&QueuePage {
+        total: state.unstaking_queue.len(),
+        offset,
+        requests: state
+            .unstaking_queue
+            .iter()
+            .skip(offset)
+            .take(limit.min(MAX_QUEUE_PAGE_SIZE))
+            .collect(),
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).expect("failed to encode JSON, should not happen")
+}
+
+/// Rebuild the changed responses of the JSON API and certify their hashes.
+///
+/// Only the paths without query parameters are certified: `/api/pool`, `/api/queue` (the first
+/// page) and `/api/stakers/{eth_address}` of every staker. The responses of the pool and of the
+/// queue are rebuilt on every refresh, those of the stakers only after `staker_changed`.
+fn certify_responses() {
+    let changed_stakers = __CHANGED_STAKERS.with(|changed| changed.take());
+    let responses: Vec<(String, Option<Vec<u8>>)> = state::read_state(|state| {
+        let mut responses = vec![
+            ("/api/pool".to_string(), Some(pool_json(state))),
+            (
+                "/api/queue".to_string(),
+                Some(queue_json(state, 0, MAX_QUEUE_PAGE_SIZE)),
+            ),
+        ];
+        for eth_address in &changed_stakers {
+            responses.push((
+                format!("/api/stakers/{}", eth_address),
+                staker_json(state, eth_address),
+            ));
+        }
+        responses
+    });
+    let asset_hashes: Vec<(String, Option<Hash>)> = __CERTIFIED_RESPONSES.with(|certified| {
+        let mut certified = certified.borrow_mut();
+        let mut asset_hashes = vec![];
+        for (path, body) in responses {
+            if certified.get(&path) == body.as_ref() {
+                continue;
+            }
+            let hash = body.as_ref().map(|body| Sha256::digest(body).into());
+            match body {
+                Some(body) => certified.insert(path.clone(), body),
+                None => certified.remove(&path),
+            };
+            asset_hashes.push((path, hash));
+        }
+        asset_hashes
+    });
+    if !asset_hashes.is_empty() {
+        certification::update_http_assets(asset_hashes);
+    }
+}
+
+/// The `IC-Certificate` header proving the hash of the response at a path, if the call is a
+/// query with a data certificate.
+fn certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    Some((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(certification::http_asset_witness(path))
+        ),
+    ))
+}
+
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn query_param(query: &str, name: &str) -> Option<usize> {
+    query_value(query, name).and_then(|value| value.parse().ok())
+}
+
+/// Serve an HTTP request.
+///
+/// The responses of the JSON API at the certified paths are the ones certified by the last
+/// refresh, so they can lag behind the state by up to `CERTIFICATION_INTERVAL`, or longer while
+/// the cycles balance is low. The other responses, such as the pages of the queue selected by
+/// query parameters, or the responses of the stakers at another form of their address than the
+/// canonical one (see `canonical_staker_address`), are not certified.
+pub fn serve(req: HttpRequest) -> HttpResponse {
+    let (path, query) = req.url.split_once('?').unwrap_or((req.url.as_str(), ""));
+    if path == "/metrics" {
+        let now_millis = crate::time() as i64 / 1_000_000;
+        let mut writer = ic_metrics_encoder::MetricsEncoder::new(vec![], now_millis);
+        return match metrics::encode_metrics(&mut writer) {
+            Ok(()) => response(200, "text/plain; version=0.0.4", writer.into_inner()),
+            Err(err) => response(
+                500,
+                "text/plain",
+                format!("failed to encode metrics: {}", err).into_bytes(),
+            ),
+        };
+    }
+    if path == "/logs" {
+        let min_level = query_value(query, "level").and_then(|level| match level {
+            "INFO" => Some(LogLevel::Info),
+            "WARN" => Some(LogLevel::Warn),
+            "ERROR" => Some(LogLevel::Error),
+            _ => None,
+        });
+        let since = query_value(query, "since").and_then(|since| since.parse().ok());
+        return response(
+            200,
+            JSON_CONTENT_TYPE,
+            to_json(&log::get_logs(min_level, since)),
+        );
+    }
+    if !path.starts_with("/api/") {
+        return response(404, "text/plain", b"not found".to_vec());
+    }
+    // The records of the stakers are keyed by the canonical form of their address, so the other
+    // forms are served from the state, without a certificate.
+    let staker_address = path
+        .strip_prefix("/api/stakers/")
+        .map(crate::canonical_staker_address);
+    if let Some(None) = staker_address {
+        return response(404, "text/plain", b"not found".to_vec());
+    }
+    if query.is_empty() {
+        let certified =
+            __CERTIFIED_RESPONSES.with(|certified| certified.borrow().get(path).cloned());
+        if let Some(body) = certified {
+            let mut certified_response = response(200, JSON_CONTENT_TYPE, body);
+            certified_response.headers.extend(certificate_header(path));
+            return certified_response;
+        }
+    }
+    let body = state::read_state(|state| match path {
+        "/api/pool" => Some(pool_json(state)),
+        "/api/queue" => Some(queue_json(
+            state,
+            query_param(query, "offset").unwrap_or(0),
+            query_param(query, "limit").unwrap_or(MAX_QUEUE_PAGE_SIZE),
+        )),
+        _ => staker_address
+            .flatten()
+            .and_then(|address| staker_json(state, &address)),
+    });
+    match body {
+        Some(body) => response(200, JSON_CONTENT_TYPE, body),
+        None => response(404, "text/plain", b"not found".to_vec()),
+    }
+}
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: ByteBuf::from(body),
+    }
+}
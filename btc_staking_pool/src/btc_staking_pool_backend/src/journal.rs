@@ -0,0 +1,1184 @@
+use crate::bridge;
+use crate::certification;
+use crate::icrc::{Account, TransferArg, TransferError};
+use crate::ledger::{self, MemoAction};
+use crate::log;
+use crate::state::{
+    self, BtcStakingPoolState, Destination, Operation, OperationError, OperationKind,
+    OperationStatus, OperationStep, PoolTransfer, PoolTransferKind, StakerLock, UnstakeRequest,
+};
+use alloc::collections::BTreeSet;
+use core::{cell::RefCell, time::Duration};
+use ic_ledger_types::Subaccount;
+
+const RECOVERY_TIMER_INTERVAL: Duration = Duration::from_secs(60);
+const OPERATION_RETENTION_PERIOD: u64 = 60 * 60 * 24 * 30 * 1000000000; // 30 days, in nano seconds
+
+thread_local! {
+    static __EXECUTING: RefCell<BTreeSet<u64>> = RefCell::default();
+}
+
+/// Marks an operation as being executed, so that it is not executed concurrently.
+struct ExecutionGuard(u64);
+
+impl ExecutionGuard {
+    fn new(operation_id: u64) -> Option<Self> {
+        __EXECUTING
+            .with(|e| e.borrow_mut().insert(operation_id))
+            .then_some(Self(operation_id))
+    }
+}
+
+impl Drop for ExecutionGuard {
+    fn drop(&mut self) {
+        __EXECUTING.with(|e| e.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Holds the lock of a staker while one of its balances is synced with a ledger.
+pub struct BalanceSyncGuard(String);
+
+impl Drop for BalanceSyncGuard {
+    fn drop(&mut self) {
+        state::mutate_state(|state| state.staker_locks.remove(&self.0));
+    }
+}
+
+/// Lock a staker for syncing one of its balances, or `None` if it is locked already.
+pub fn lock_for_balance_sync(eth_address: &str) -> Option<BalanceSyncGuard> {
+    state::mutate_state(|state| {
+        if state.staker_locks.contains_key(eth_address) {
+            return None;
+        }
+        state
+            .staker_locks
+            .insert(eth_address.to_string(), StakerLock::BalanceSync);
+        Some(BalanceSyncGuard(eth_address.to_string()))
+    })
+}
+
+/// The result of executing an operation.
+pub enum Outcome {
+    Completed(Operation),
+    /// The operation failed, and the finished steps are compensated if needed.
+    Failed(OperationError),
+    /// The operation is left for the recovery timer, which completes or compensates it.
+    Pending {
+        operation_id: u64,
+        reason: String,
+    },
+}
+
+/// The result of a transfer of the pool, see `run_pool_transfer`.
+pub enum PoolTransferOutcome {
+    Completed,
+    /// The ledger rejected the transfer, and the reserved amount is released.
+    Rejected(String),
+    /// The outcome of the transfer is unknown, so the amount stays reserved and the transfer is
+    /// retried by the recovery timer.
+    Pending(String),
+}
+
+/// Start the timer which periodically resumes the unfinished operations.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(RECOVERY_TIMER_INTERVAL, || {
+        ic_cdk::spawn(recover_operations())
+    });
+}
+
+/// Allocate a new operation id, which is also used as the nonce in the memos of the transfers
+/// of the pool which are not journaled.
+pub fn next_operation_id(state: &mut BtcStakingPoolState) -> u64 {
+    let id = state.next_operation_id;
+    state.next_operation_id += 1;
+    id
+}
+
+/// A new operation of a staker, see `create_operation`.
+pub struct OperationRequest<'a> {
+    pub kind: OperationKind,
+    pub eth_address: &'a str,
+    pub subaccount: Subaccount,
+    pub amount: u64,
+    /// The ledger fee paid by the staker.
+    pub fee: u64,
+    pub protocol_fee: u64,
+    /// Where the tokens are sent, if chosen by the staker.
+    pub destination: Option<Destination>,
+    /// The staker nonce consumed by a signed operation, or `None` for the operation id.
+    pub nonce: Option<u64>,
+}
+
+/// Record a new operation of a staker, and lock the staker, as well as the receiving staker of a
+/// transfer between stakers, until the operation is finished.
+///
+/// The caller must make sure that the locked stakers are not locked already.
+pub fn create_operation(state: &mut BtcStakingPoolState, request: OperationRequest) -> u64 {
+    let OperationRequest {
+        kind,
+        eth_address,
+        subaccount,
+        amount,
+        fee,
+        protocol_fee,
+        destination,
+        nonce,
+    } = request;
+    let id = next_operation_id(state);
+    let now = crate::time();
+    let (step, action) = match kind {
+        OperationKind::Stake => (OperationStep::TransferCkbtcToPool, MemoAction::Stake),
+        OperationKind::Unstake => (OperationStep::BurnOtbtc, MemoAction::Unstake),
+        OperationKind::UnlockTokens => (
+            OperationStep::TransferCkbtcToStaker,
+            MemoAction::UnlockTokens,
+        ),
+        OperationKind::WithdrawBtc => (OperationStep::BurnCkbtc, MemoAction::WithdrawBtc),
+        OperationKind::TransferOtbtc => (
+            OperationStep::TransferOtbtcToRecipient,
+            MemoAction::TransferOtbtc,
+        ),
+        OperationKind::ExportOtbtc => (
+            OperationStep::TransferOtbtcToAccount,
+            MemoAction::ExportOtbtc,
+        ),
+        OperationKind::WithdrawCkbtc => (
+            OperationStep::TransferCkbtcToAccount,
+            MemoAction::WithdrawCkbtc,
+        ),
+        OperationKind::BridgeOtbtc => (
+            OperationStep::TransferOtbtcToBridge,
+            MemoAction::BridgeOtbtc,
+        ),
+        OperationKind::RedeemOtbtc => (
+            OperationStep::TransferOtbtcFromBridge,
+            MemoAction::RedeemOtbtc,
+        ),
+        OperationKind::DistributeRewards => {
+            (OperationStep::MintOtbtc, MemoAction::DistributeRewards)
+        }
+    };
+    state.operations.insert(
+        id,
+        Operation {
+            id,
+            kind,
+            eth_address: eth_address.to_string(),
+            subaccount,
+            amount,
+            fee,
+            protocol_fee,
+            destination,
+            memo: ledger::memo(action, nonce.unwrap_or(id)),
+            step,
+            step_created_at: now,
+            status: OperationStatus::InProgress,
+            attempts: 0,
+            step_outcome_unknown: false,
+            needs_operator: false,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        },
+    );
+    let op = state.operations[&id].clone();
+    lock_stakers(state, &op);
+    id
+}
+
+/// The stakers whose balances are changed by an operation: the staker, and the receiving staker
+/// of a transfer between stakers, whose balance must not be synced with the ledger meanwhile.
+pub fn locked_stakers(op: &Operation) -> impl Iterator<Item = &str> {
+    let recipient = match &op.destination {
+        Some(Destination::Staker(recipient)) => Some(recipient.as_str()),
+        _ => None,
+    };
+    core::iter::once(op.eth_address.as_str()).chain(recipient)
+}
+
+fn lock_stakers(state: &mut BtcStakingPoolState, op: &Operation) {
+    for eth_address in locked_stakers(op) {
+        state
+            .staker_locks
+            .insert(eth_address.to_string(), StakerLock::Operation(op.id));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Ledger {
+    Ckbtc,
+    Otbtc,
+}
+
+/// The transfer of the current step of an operation, or `None` if there is nothing to transfer.
+fn step_transfer(state: &BtcStakingPoolState, op: &Operation) -> Option<(Ledger, TransferArg)> {
+    let pool = ic_cdk::id();
+    let pool_account = |subaccount: Option<Subaccount>| Account {
+        owner: pool,
+        subaccount,
+    };
+    let (ledger, from_subaccount, to, amount, fee) = match &op.step {
+        OperationStep::TransferCkbtcToPool => (
+            Ledger::Ckbtc,
+            Some(op.subaccount),
+            pool_account(None),
+            op.amount,
+            op.fee,
+        ),
+        // Minting is free.
+        OperationStep::MintOtbtc => (
+            Ledger::Otbtc,
+            None,
+            pool_account(Some(op.subaccount)),
+            op.amount,
+            0,
+        ),
+        OperationStep::ReturnCkbtc { .. } | OperationStep::TransferCkbtcToStaker => (
+            Ledger::Ckbtc,
+            None,
+            pool_account(Some(op.subaccount)),
+            op.amount.saturating_sub(op.fee),
+            op.fee,
+        ),
+        // Burning is free.
+        OperationStep::BurnOtbtc => (
+            Ledger::Otbtc,
+            Some(op.subaccount),
+            pool_account(None),
+            op.amount,
+            0,
+        ),
+        OperationStep::BurnCkbtc => (
+            Ledger::Ckbtc,
+            Some(op.subaccount),
+            Account {
+                owner: state.ckbtc_minting_account,
+                subaccount: None,
+            },
+            op.amount - op.protocol_fee,
+            0,
+        ),
+        OperationStep::TransferProtocolFee => (
+            Ledger::Ckbtc,
+            Some(op.subaccount),
+            pool_account(Some(crate::treasury_subaccount())),
+            op.protocol_fee,
+            op.fee,
+        ),
+        OperationStep::TransferOtbtcToRecipient => (
+            Ledger::Otbtc,
+            Some(op.subaccount),
+            pool_account(Some(state.stakers_map[recipient(op)].subaccount)),
+            op.amount,
+            op.fee,
+        ),
+        OperationStep::TransferOtbtcToAccount => (
+            Ledger::Otbtc,
+            Some(op.subaccount),
+            destination_account(op),
+            op.amount,
+            op.fee,
+        ),
+        OperationStep::TransferCkbtcToAccount => (
+            Ledger::Ckbtc,
+            Some(op.subaccount),
+            destination_account(op),
+            op.amount - op.protocol_fee,
+            op.fee,
+        ),
+        OperationStep::TransferOtbtcToBridge => (
+            Ledger::Otbtc,
+            Some(op.subaccount),
+            pool_account(Some(bridge::bridge_subaccount())),
+            op.amount,
+            op.fee,
+        ),
+        OperationStep::TransferOtbtcFromBridge => (
+            Ledger::Otbtc,
+            Some(bridge::bridge_subaccount()),
+            pool_account(Some(op.subaccount)),
+            op.amount - op.fee,
+            op.fee,
+        ),
+    };
+    if amount == 0 {
+        return None;
+    }
+    Some((
+        ledger,
+        // The same memo and creation time are used when the step is retried, so that the ledger
+        // deduplicates the transfer if an earlier attempt went through.
+        TransferArg {
+            from_subaccount,
+            to,
+            amount: amount.into(),
+            fee: Some(fee.into()),
+            memo: ledger::memo_bytes(op.memo),
+            created_at_time: Some(op.step_created_at),
+        },
+    ))
+}
+
+/// The account outside the pool receiving the tokens of an operation.
+fn destination_account(op: &Operation) -> Account {
+    match &op.destination {
+        Some(Destination::Account(account)) => account.clone(),
+        _ => panic!("destination account not found, should not happen"),
+    }
+}
+
+/// The receiving staker of a transfer between stakers.
+fn recipient(op: &Operation) -> &str {
+    match &op.destination {
+        Some(Destination::Staker(recipient)) => recipient,
+        _ => panic!("recipient not found, should not happen"),
+    }
+}
+
+/// Apply the effect of the finished step of an operation to the state, move it to the next
+/// step, and certify the new balances.
+fn complete_step(state: &mut BtcStakingPoolState, op: &Operation) {
+    apply_step(state, op);
+    certification::certify_staker(state, &op.eth_address);
+    if let Some(Destination::Staker(recipient)) = &op.destination {
+        certification::certify_staker(state, recipient);
+    }
+}
+
+fn apply_step(state: &mut BtcStakingPoolState, op: &Operation) {
+    let staker = state
+        .stakers_map
+        .get_mut(&op.eth_address)
+        .expect("staker not found, should not happen");
+    let next_status = match &op.step {
+        OperationStep::TransferCkbtcToPool => {
+            staker.ckbtc_balance -= op.amount + op.fee;
+            state.total_ckbtc_in_pool += op.amount;
+            advance(state, op.id, OperationStep::MintOtbtc);
+            return;
+        }
+        OperationStep::MintOtbtc => {
+            staker.otbtc_balance += op.amount;
+            OperationStatus::Completed
+        }
+        OperationStep::ReturnCkbtc { cause } => {
+            // An amount which does not cover the fee is left in the main account.
+            staker.ckbtc_balance += op.amount.saturating_sub(op.fee);
+            state.total_ckbtc_in_pool -= op.amount;
+            OperationStatus::Compensated(cause.clone())
+        }
+        OperationStep::BurnOtbtc => {
+            staker.otbtc_balance -= op.amount;
+            state.unstaking_queue.push_back(UnstakeRequest {
+                eth_address: op.eth_address.clone(),
+                amount: op.amount,
+                unlock_time: crate::time() + state.unbonding_period,
+            });
+            OperationStatus::Completed
+        }
+        OperationStep::TransferCkbtcToStaker => {
+            staker.ckbtc_balance += op.amount - op.fee;
+            state.total_ckbtc_in_pool -= op.amount;
+            OperationStatus::Completed
+        }
+        OperationStep::BurnCkbtc => {
+            staker.ckbtc_balance -= op.amount - op.protocol_fee;
+            if op.protocol_fee > 0 {
+                advance(state, op.id, OperationStep::TransferProtocolFee);
+                return;
+            }
+            OperationStatus::Completed
+        }
+        OperationStep::TransferProtocolFee => {
+            staker.ckbtc_balance -= op.protocol_fee + op.fee;
+            state.treasury_balance += op.protocol_fee;
+            OperationStatus::Completed
+        }
+        OperationStep::TransferOtbtcToRecipient => {
+            staker.otbtc_balance -= op.amount + op.fee;
+            let recipient = state
+                .stakers_map
+                .get_mut(recipient(op))
+                .expect("recipient not found, should not happen");
+            recipient.otbtc_balance += op.amount;
+            OperationStatus::Completed
+        }
+        OperationStep::TransferOtbtcToAccount => {
+            staker.otbtc_balance -= op.amount + op.fee;
+            OperationStatus::Completed
+        }
+        OperationStep::TransferCkbtcToAccount => {
+            staker.ckbtc_balance -= op.amount - op.protocol_fee + op.fee;
+            if op.protocol_fee > 0 {
+                advance(state, op.id, OperationStep::TransferProtocolFee);
+                return;
+            }
+            OperationStatus::Completed
+        }
+        OperationStep::TransferOtbtcToBridge => {
+            staker.otbtc_balance -= op.amount + op.fee;
+            state.bridged_otbtc += op.amount;
+            bridge::record_voucher(state, op);
+            OperationStatus::Completed
+        }
+        // The amount was deducted from `bridged_otbtc` when the burn was redeemed.
+        OperationStep::TransferOtbtcFromBridge => {
+            staker.otbtc_balance += op.amount - op.fee;
+            OperationStatus::Completed
+        }
+    };
+    finish(state, op.id, next_status);
+}
+
+/// How a step of an operation failed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// The call to the ledger failed, so the transfer may have gone through.
+    CallFailed,
+    /// The ledger did not execute the transfer, but may do so when it is retried.
+    Transient,
+    /// The ledger refused the transfer as its creation time is out of the deduplication window.
+    TooOld,
+    /// The ledger rejected the transfer.
+    Rejected,
+}
+
+/// Handle a failed step of an operation.
+///
+/// A step whose call failed may have gone through, so it is retried with the same arguments,
+/// which the ledger deduplicates, until the ledger answers. A step rejected by the ledger is not
+/// retried: the operation fails if nothing has been changed yet, or the finished steps are
+/// compensated, and a step which can neither fail nor be compensated is left to an operator.
+fn fail_step(
+    state: &mut BtcStakingPoolState,
+    op: &Operation,
+    error: OperationError,
+    failure: Failure,
+) {
+    let operation = state
+        .operations
+        .get_mut(&op.id)
+        .expect("operation not found, should not happen");
+    operation.attempts += 1;
+    operation.last_error = Some(error.clone());
+    operation.updated_at = crate::time();
+    if failure == Failure::CallFailed {
+        operation.step_outcome_unknown = true;
+    }
+    state
+        .operation_metrics
+        .entry(op.kind)
+        .or_default()
+        .last_error_time = Some(operation.updated_at);
+    log::warn(
+        &log_context(op),
+        format!(
+            "step {:?} failed (attempt {}): {:?}",
+            op.step,
+            op.attempts + 1,
+            error
+        ),
+    );
+    match failure {
+        Failure::CallFailed | Failure::Transient => return,
+        // Every attempt was answered by the ledger without executing the transfer, so it is
+        // retried as a new transfer.
+        Failure::TooOld if !op.step_outcome_unknown => {
+            renew_transfer(state, op.id);
+            return;
+        }
+        // An earlier attempt may have gone through, which cannot be checked anymore.
+        Failure::TooOld => {
+            stop(state, op.id, error);
+            return;
+        }
+        Failure::Rejected => {}
+    }
+    match &op.step {
+        OperationStep::TransferCkbtcToPool
+        | OperationStep::BurnOtbtc
+        | OperationStep::BurnCkbtc
+        | OperationStep::TransferOtbtcToRecipient
+        | OperationStep::TransferOtbtcToAccount
+        | OperationStep::TransferCkbtcToAccount
+        | OperationStep::TransferOtbtcToBridge => {
+            finish(state, op.id, OperationStatus::Failed(error))
+        }
+        // The share of a staker in a distribution is left for the next distribution.
+        OperationStep::MintOtbtc if op.kind == OperationKind::DistributeRewards => {
+            state.total_ckbtc_in_pool -= op.amount;
+            certification::certify_pool(state);
+            finish(state, op.id, OperationStatus::Failed(error));
+        }
+        OperationStep::MintOtbtc => {
+            advance(state, op.id, OperationStep::ReturnCkbtc { cause: error });
+        }
+        // The ckBTC must be returned to the staker, and the ERC-20 tokens of a redemption have
+        // been burnt, so these steps cannot fail.
+        OperationStep::ReturnCkbtc { .. } | OperationStep::TransferOtbtcFromBridge => {
+            stop(state, op.id, error)
+        }
+        OperationStep::TransferCkbtcToStaker => {
+            // Put the request back, to be unlocked again later.
+            state.unstaking_queue.push_front(UnstakeRequest {
+                eth_address: op.eth_address.clone(),
+                amount: op.amount,
+                unlock_time: op.created_at,
+            });
+            finish(state, op.id, OperationStatus::Failed(error));
+        }
+        // The BTC or ckBTC has been withdrawn already, so the protocol fee is not charged.
+        OperationStep::TransferProtocolFee => {
+            let operation = state
+                .operations
+                .get_mut(&op.id)
+                .expect("operation not found, should not happen");
+            operation.amount -= operation.protocol_fee;
+            operation.protocol_fee = 0;
+            // The fee of a BTC withdrawal is only paid for moving the protocol fee, while the
+            // fee of a ckBTC withdrawal is also paid for the withdrawn ckBTC.
+            if operation.kind == OperationKind::WithdrawBtc {
+                operation.fee = 0;
+            }
+            finish(state, op.id, OperationStatus::Completed);
+        }
+    }
+}
+
+fn advance(state: &mut BtcStakingPoolState, operation_id: u64, step: OperationStep) {
+    let operation = state
+        .operations
+        .get_mut(&operation_id)
+        .expect("operation not found, should not happen");
+    operation.step = step;
+    operation.attempts = 0;
+    operation.step_outcome_unknown = false;
+    operation.step_created_at = crate::time();
+    operation.updated_at = operation.step_created_at;
+}
+
+/// Retry the current step of an operation as a new transfer, with a new creation time and a new
+/// nonce in the memo. Only safe once no earlier attempt of the step can have gone through.
+fn renew_transfer(state: &mut BtcStakingPoolState, operation_id: u64) {
+    let nonce = next_operation_id(state);
+    let operation = state
+        .operations
+        .get_mut(&operation_id)
+        .expect("operation not found, should not happen");
+    operation.memo = ledger::with_nonce(operation.memo, nonce);
+    operation.attempts = 0;
+    operation.step_outcome_unknown = false;
+    operation.step_created_at = crate::time();
+    operation.updated_at = operation.step_created_at;
+    log::info(
+        &log_context(operation),
+        format!("renewed the transfer of step {:?}", operation.step),
+    );
+}
+
+/// Fail an operation at a step which can neither be retried nor compensated automatically, and
+/// leave it to the controllers, see `resolve_operation`.
+fn stop(state: &mut BtcStakingPoolState, operation_id: u64, error: OperationError) {
+    state
+        .operations
+        .get_mut(&operation_id)
+        .expect("operation not found, should not happen")
+        .needs_operator = true;
+    log::error(
+        &format!("operation {}", operation_id),
+        "left to the controllers, see resolve_operation".to_string(),
+    );
+    finish(state, operation_id, OperationStatus::Failed(error));
+}
+
+/// Resume an operation left to the controllers, after they checked on the ledger whether the
+/// transfer of its current step went through: the step is completed if it did, or retried as a
+/// new transfer otherwise. The caller must make sure that the locked stakers are not locked.
+pub fn resolve_operation(state: &mut BtcStakingPoolState, operation_id: u64, transferred: bool) {
+    let operation = state
+        .operations
+        .get_mut(&operation_id)
+        .expect("operation not found, should not happen");
+    operation.status = OperationStatus::InProgress;
+    operation.needs_operator = false;
+    let op = operation.clone();
+    lock_stakers(state, &op);
+    log::info(
+        &log_context(&op),
+        format!("resolved step {:?}, transferred: {}", op.step, transferred),
+    );
+    if transferred {
+        complete_step(state, &op);
+    } else {
+        renew_transfer(state, operation_id);
+    }
+}
+
+fn log_context(op: &Operation) -> String {
+    format!("operation {} ({:?} of {})", op.id, op.kind, op.eth_address)
+}
+
+fn finish(state: &mut BtcStakingPoolState, operation_id: u64, status: OperationStatus) {
+    let operation = state
+        .operations
+        .get_mut(&operation_id)
+        .expect("operation not found, should not happen");
+    let metrics = state.operation_metrics.entry(operation.kind).or_default();
+    match status {
+        OperationStatus::Completed => {
+            metrics.succeeded += 1;
+            log::info(&log_context(operation), "completed".to_string());
+        }
+        _ => {
+            metrics.failed += 1;
+            log::error(
+                &log_context(operation),
+                format!("finished with {:?}", status),
+            );
+        }
+    }
+    operation.status = status;
+    operation.updated_at = crate::time();
+    for eth_address in locked_stakers(operation) {
+        state.staker_locks.remove(eth_address);
+    }
+}
+
+/// Execute the remaining steps of an operation.
+///
+/// The state is updated right after each finished step, so that an operation interrupted
+/// at any point can be resumed by the recovery timer.
+pub async fn run(operation_id: u64) -> Outcome {
+    let Some(_guard) = ExecutionGuard::new(operation_id) else {
+        return Outcome::Pending {
+            operation_id,
+            reason: "the operation is being executed".to_string(),
+        };
+    };
+    loop {
+        let (op, transfer, ledger_accounts) = state::read_state(|state| {
+            let op = state
+                .operations
+                .get(&operation_id)
+                .cloned()
+                .expect("operation not found, should not happen");
+            let transfer = step_transfer(state, &op);
+            (
+                op,
+                transfer,
+                (state.ckbtc_ledger_account, state.otbtc_ledger_account),
+            )
+        });
+        match op.status {
+            OperationStatus::InProgress => {}
+            OperationStatus::Completed => return Outcome::Completed(op),
+            OperationStatus::Failed(error) | OperationStatus::Compensated(error) => {
+                return Outcome::Failed(error)
+            }
+        }
+        let Some((ledger, transfer_args)) = transfer else {
+            state::mutate_state(|state| complete_step(state, &op));
+            continue;
+        };
+        let (ledger_account, name) = match ledger {
+            Ledger::Ckbtc => (ledger_accounts.0, "ckBTC"),
+            Ledger::Otbtc => (ledger_accounts.1, "otBTC"),
+        };
+        let (error, failure) = match ledger::transfer(ledger_account, transfer_args).await {
+            Ok(Ok(_)) => {
+                state::mutate_state(|state| complete_step(state, &op));
+                continue;
+            }
+            Ok(Err(e)) => {
+                if let TransferError::BadFee { expected_fee } = &e {
+                    // The later operations pay the fee expected by the ledger, which changed
+                    // since the last sync.
+                    let expected_fee = u64::try_from(expected_fee.0.clone()).unwrap_or(u64::MAX);
+                    state::mutate_state(|state| match ledger {
+                        Ledger::Ckbtc => state.ckbtc_transfer_fee = expected_fee,
+                        Ledger::Otbtc => state.otbtc_transfer_fee = expected_fee,
+                    });
+                }
+                let failure = match e {
+                    TransferError::TemporarilyUnavailable
+                    | TransferError::CreatedInFuture { .. } => Failure::Transient,
+                    TransferError::TooOld => Failure::TooOld,
+                    _ => Failure::Rejected,
+                };
+                let message = format!("{} ledger transfer error {:?}", name, e);
+                let error = match ledger {
+                    Ledger::Ckbtc => OperationError::CkbtcTransferError(message),
+                    Ledger::Otbtc => OperationError::OtbtcTransferError(message),
+                };
+                (error, failure)
+            }
+            Err(e) => {
+                let message = format!("failed to call {} ledger: {:?}", name, e);
+                let error = match ledger {
+                    Ledger::Ckbtc => OperationError::CkbtcLedgerError(message),
+                    Ledger::Otbtc => OperationError::OtbtcLedgerError(message),
+                };
+                (error, Failure::CallFailed)
+            }
+        };
+        let retried_later = state::mutate_state(|state| {
+            fail_step(state, &op, error.clone(), failure);
+            let operation = &state.operations[&operation_id];
+            operation.status == OperationStatus::InProgress && operation.step == op.step
+        });
+        if retried_later {
+            // Leave the retry of the step to the recovery timer.
+            return Outcome::Pending {
+                operation_id,
+                reason: format!("{:?}", error),
+            };
+        }
+    }
+}
+
+/// Resume the unfinished operations, and drop the finished operations which are older than
+/// `OPERATION_RETENTION_PERIOD`, unless they are left to the controllers.
+async fn recover_operations() {
+    let now = crate::time();
+    let operation_ids: Vec<u64> = state::mutate_state(|state| {
+        state.operations.retain(|_, op| {
+            op.status == OperationStatus::InProgress
+                || op.needs_operator
+                || now < op.updated_at.saturating_add(OPERATION_RETENTION_PERIOD)
+        });
+        state
+            .operations
+            .values()
+            .filter(|op| op.status == OperationStatus::InProgress)
+            .map(|op| op.id)
+            .collect()
+    });
+    for operation_id in operation_ids {
+        let _ = run(operation_id).await;
+    }
+    let nonces: Vec<u64> =
+        state::read_state(|state| state.pool_transfers.keys().copied().collect());
+    for nonce in nonces {
+        let _ = execute_pool_transfer(nonce).await;
+    }
+}
+
+/// Execute a ckBTC transfer of the pool which is not part of an operation of a staker, whose
+/// amount and fee are reserved by the caller, with an id from `next_operation_id` as the nonce.
+///
+/// The transfer is recorded until the ledger answers, so that a transfer whose call failed,
+/// which may have gone through, is retried by the recovery timer with the same arguments. The
+/// reservation is only released when the ledger rejects the transfer.
+pub async fn run_pool_transfer(nonce: u64, transfer: PoolTransfer) -> PoolTransferOutcome {
+    state::mutate_state(|state| state.pool_transfers.insert(nonce, transfer));
+    execute_pool_transfer(nonce).await
+}
+
+async fn execute_pool_transfer(nonce: u64) -> PoolTransferOutcome {
+    let Some(_guard) = ExecutionGuard::new(nonce) else {
+        return PoolTransferOutcome::Pending("the transfer is being executed".to_string());
+    };
+    let Some((transfer, ckbtc_ledger_account)) = state::read_state(|state| {
+        state
+            .pool_transfers
+            .get(&nonce)
+            .map(|transfer| (transfer.clone(), state.ckbtc_ledger_account))
+    }) else {
+        return PoolTransferOutcome::Completed;
+    };
+    let args = TransferArg {
+        from_subaccount: transfer.from_subaccount,
+        to: transfer.to.clone(),
+        amount: transfer.amount.into(),
+        fee: Some(transfer.fee.into()),
+        memo: ledger::memo_bytes(transfer.memo),
+        created_at_time: Some(transfer.created_at),
+    };
+    let log_context = format!("{:?} {}", transfer.kind, nonce);
+    let (completed, message) = match ledger::transfer(ckbtc_ledger_account, args).await {
+        Ok(Ok(_)) => (true, String::new()),
+        Ok(Err(
+            e @ (TransferError::TemporarilyUnavailable | TransferError::CreatedInFuture { .. }),
+        )) => {
+            return PoolTransferOutcome::Pending(format!("ckBTC ledger transfer error {:?}", e));
+        }
+        Err(e) => {
+            let message = format!("failed to call ckBTC ledger: {:?}", e);
+            log::warn(&log_context, message.clone());
+            return PoolTransferOutcome::Pending(message);
+        }
+        Ok(Err(TransferError::TooOld)) => {
+            // An earlier attempt may have gone through, so the amount stays reserved.
+            let message = "the transfer is too old to be retried, check it on the ledger";
+            log::error(&log_context, message.to_string());
+            state::mutate_state(|state| state.pool_transfers.remove(&nonce));
+            return PoolTransferOutcome::Pending(message.to_string());
+        }
+        Ok(Err(e)) => (false, format!("ckBTC ledger transfer error {:?}", e)),
+    };
+    state::mutate_state(|state| {
+        if state.pool_transfers.remove(&nonce).is_none() {
+            return;
+        }
+        match transfer.kind {
+            PoolTransferKind::WithdrawTreasury if !completed => {
+                state.treasury_balance += transfer.amount + transfer.fee;
+            }
+            PoolTransferKind::WithdrawTreasury => {}
+            PoolTransferKind::ProtocolFee => {
+                state.total_ckbtc_in_pool -= transfer.amount + transfer.fee;
+                if completed {
+                    state.treasury_balance += transfer.amount;
+                }
+            }
+        }
+        certification::certify_pool(state);
+    });
+    if completed {
+        PoolTransferOutcome::Completed
+    } else {
+        log::error(&log_context, message.clone());
+        PoolTransferOutcome::Rejected(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Staker;
+    use crate::types::InitArgs;
+    use candid::Principal;
+
+    const STAKER: &str = "7e5f4552091a69125d5dfcb7b8c2659029395bdf";
+    const RECIPIENT: &str = "2b5ad5c4795c026514f8317c7a215e218dccd6cf";
+    const FEE: u64 = 10;
+
+    /// A state with two stakers, with the given balances for the first one.
+    fn state_with_staker(ckbtc_balance: u64, otbtc_balance: u64) -> BtcStakingPoolState {
+        let mut state = crate::initial_state(InitArgs {
+            ckbtc_minting_account: Principal::anonymous(),
+            ckbtc_ledger_account: Principal::anonymous(),
+            otbtc_ledger_account: Principal::anonymous(),
+            deposit_scan_idle_period: None,
+            btc_network: None,
+            ckbtc_minter_ecdsa_key_name: None,
+            ckbtc_transfer_fee: Some(FEE),
+            otbtc_transfer_fee: Some(FEE),
+        });
+        for (i, eth_address) in [STAKER, RECIPIENT].into_iter().enumerate() {
+            let staker = Staker::new(eth_address.to_string(), Subaccount([i as u8 + 1; 32]));
+            state.stakers_map.insert(eth_address.to_string(), staker);
+        }
+        let staker = state.stakers_map.get_mut(STAKER).unwrap();
+        staker.ckbtc_balance = ckbtc_balance;
+        staker.otbtc_balance = otbtc_balance;
+        state
+    }
+
+    fn create(
+        state: &mut BtcStakingPoolState,
+        kind: OperationKind,
+        amount: u64,
+        protocol_fee: u64,
+        destination: Option<Destination>,
+    ) -> u64 {
+        let subaccount = state.stakers_map[STAKER].subaccount;
+        create_operation(
+            state,
+            OperationRequest {
+                kind,
+                eth_address: STAKER,
+                subaccount,
+                amount,
+                fee: FEE,
+                protocol_fee,
+                destination,
+                nonce: None,
+            },
+        )
+    }
+
+    /// Complete the current step of an operation, as if its transfer went through.
+    fn complete(state: &mut BtcStakingPoolState, operation_id: u64) {
+        let op = state.operations[&operation_id].clone();
+        complete_step(state, &op);
+    }
+
+    fn fail(state: &mut BtcStakingPoolState, operation_id: u64, failure: Failure) {
+        let op = state.operations[&operation_id].clone();
+        let error = OperationError::CkbtcTransferError("error".to_string());
+        fail_step(state, &op, error, failure);
+    }
+
+    fn balances(state: &BtcStakingPoolState, eth_address: &str) -> (u64, u64) {
+        let staker = &state.stakers_map[eth_address];
+        (staker.ckbtc_balance, staker.otbtc_balance)
+    }
+
+    fn is_failed(state: &BtcStakingPoolState, operation_id: u64) -> bool {
+        matches!(
+            state.operations[&operation_id].status,
+            OperationStatus::Failed(_)
+        )
+    }
+
+    #[test]
+    fn stake_moves_ckbtc_to_pool_and_mints_otbtc() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::Stake, 500, 0, None);
+        assert!(state.staker_locks.contains_key(STAKER));
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (490, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+        assert_eq!(state.operations[&id].step, OperationStep::MintOtbtc);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (490, 500));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+        assert_eq!(state.operations[&id].status, OperationStatus::Completed);
+        assert!(state.staker_locks.is_empty());
+    }
+
+    #[test]
+    fn rejected_stake_changes_nothing() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::Stake, 500, 0, None);
+        fail(&mut state, id, Failure::Rejected);
+        assert_eq!(balances(&state, STAKER), (1_000, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 0);
+        assert!(is_failed(&state, id));
+        assert!(state.staker_locks.is_empty());
+    }
+
+    #[test]
+    fn rejected_mint_returns_ckbtc_less_the_fee() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::Stake, 500, 0, None);
+        complete(&mut state, id);
+        fail(&mut state, id, Failure::Rejected);
+        assert!(matches!(
+            state.operations[&id].step,
+            OperationStep::ReturnCkbtc { .. }
+        ));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+        complete(&mut state, id);
+        // Both transfers paid the fee.
+        assert_eq!(balances(&state, STAKER), (980, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 0);
+        assert!(matches!(
+            state.operations[&id].status,
+            OperationStatus::Compensated(_)
+        ));
+        assert!(state.staker_locks.is_empty());
+    }
+
+    #[test]
+    fn rejected_return_of_ckbtc_is_left_to_an_operator() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::Stake, 500, 0, None);
+        complete(&mut state, id);
+        fail(&mut state, id, Failure::Rejected);
+        fail(&mut state, id, Failure::Rejected);
+        assert!(state.operations[&id].needs_operator);
+        assert!(is_failed(&state, id));
+        assert_eq!(balances(&state, STAKER), (490, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+        // The controllers found the transfer on the ledger.
+        resolve_operation(&mut state, id, true);
+        assert_eq!(balances(&state, STAKER), (980, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 0);
+        assert!(!state.operations[&id].needs_operator);
+        assert!(state.staker_locks.is_empty());
+    }
+
+    #[test]
+    fn too_old_transfer_is_renewed_only_if_no_attempt_went_through() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::Stake, 500, 0, None);
+        let memo = state.operations[&id].memo;
+        fail(&mut state, id, Failure::Transient);
+        assert_eq!(state.operations[&id].attempts, 1);
+        fail(&mut state, id, Failure::TooOld);
+        let op = &state.operations[&id];
+        assert_eq!(op.status, OperationStatus::InProgress);
+        assert_eq!(op.attempts, 0);
+        assert_ne!(op.memo, memo);
+        assert_eq!(op.memo, ledger::with_nonce(memo, op.memo));
+        assert_eq!(balances(&state, STAKER), (1_000, 0));
+
+        fail(&mut state, id, Failure::CallFailed);
+        assert!(state.operations[&id].step_outcome_unknown);
+        assert_eq!(state.operations[&id].status, OperationStatus::InProgress);
+        fail(&mut state, id, Failure::TooOld);
+        assert!(state.operations[&id].needs_operator);
+        assert!(is_failed(&state, id));
+        assert_eq!(balances(&state, STAKER), (1_000, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 0);
+    }
+
+    #[test]
+    fn unstake_burns_otbtc_and_queues_the_request() {
+        let mut state = state_with_staker(0, 500);
+        state.total_ckbtc_in_pool = 500;
+        let id = create(&mut state, OperationKind::Unstake, 500, 0, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (0, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+        assert_eq!(state.unstaking_queue.len(), 1);
+        assert_eq!(state.unstaking_queue[0].amount, 500);
+        assert_eq!(state.operations[&id].status, OperationStatus::Completed);
+    }
+
+    #[test]
+    fn unlock_moves_ckbtc_from_pool_or_requeues_the_request() {
+        let mut state = state_with_staker(0, 0);
+        state.total_ckbtc_in_pool = 1_000;
+        let id = create(&mut state, OperationKind::UnlockTokens, 500, 0, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (490, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+
+        let id = create(&mut state, OperationKind::UnlockTokens, 500, 0, None);
+        fail(&mut state, id, Failure::Rejected);
+        assert_eq!(balances(&state, STAKER), (490, 0));
+        assert_eq!(state.total_ckbtc_in_pool, 500);
+        assert_eq!(state.unstaking_queue.front().unwrap().amount, 500);
+        assert!(is_failed(&state, id));
+    }
+
+    #[test]
+    fn btc_withdrawal_pays_the_protocol_fee_to_the_treasury() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::WithdrawBtc, 500, 5, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (505, 0));
+        assert_eq!(
+            state.operations[&id].step,
+            OperationStep::TransferProtocolFee
+        );
+        complete(&mut state, id);
+        // The amount includes the protocol fee, and the fee is paid for moving the protocol fee.
+        assert_eq!(balances(&state, STAKER), (490, 0));
+        assert_eq!(state.treasury_balance, 5);
+        assert_eq!(state.total_ckbtc_in_pool, 0);
+        assert_eq!(state.operations[&id].status, OperationStatus::Completed);
+    }
+
+    #[test]
+    fn rejected_protocol_fee_is_not_charged() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::WithdrawBtc, 500, 5, None);
+        complete(&mut state, id);
+        fail(&mut state, id, Failure::Rejected);
+        let op = &state.operations[&id];
+        assert_eq!((op.amount, op.protocol_fee, op.fee), (495, 0, 0));
+        assert_eq!(op.status, OperationStatus::Completed);
+        assert_eq!(balances(&state, STAKER), (505, 0));
+        assert_eq!(state.treasury_balance, 0);
+    }
+
+    #[test]
+    fn ckbtc_withdrawal_pays_the_fee_of_both_transfers() {
+        let mut state = state_with_staker(1_000, 0);
+        let id = create(&mut state, OperationKind::WithdrawCkbtc, 500, 5, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (495, 0));
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (480, 0));
+        assert_eq!(state.treasury_balance, 5);
+        assert_eq!(state.operations[&id].status, OperationStatus::Completed);
+
+        let id = create(&mut state, OperationKind::WithdrawCkbtc, 100, 5, None);
+        complete(&mut state, id);
+        fail(&mut state, id, Failure::Rejected);
+        // The withdrawn ckBTC still paid its fee, only the protocol fee is not charged.
+        assert_eq!(state.operations[&id].fee, FEE);
+        assert_eq!(balances(&state, STAKER), (375, 0));
+        assert_eq!(state.treasury_balance, 5);
+    }
+
+    #[test]
+    fn otbtc_transfer_moves_the_amount_between_stakers() {
+        let mut state = state_with_staker(0, 1_000);
+        let destination = Some(Destination::Staker(RECIPIENT.to_string()));
+        let id = create(
+            &mut state,
+            OperationKind::TransferOtbtc,
+            500,
+            0,
+            destination,
+        );
+        assert!(state.staker_locks.contains_key(RECIPIENT));
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (0, 490));
+        assert_eq!(balances(&state, RECIPIENT), (0, 500));
+        assert!(state.staker_locks.is_empty());
+
+        let destination = Some(Destination::Staker(RECIPIENT.to_string()));
+        let id = create(
+            &mut state,
+            OperationKind::TransferOtbtc,
+            100,
+            0,
+            destination,
+        );
+        fail(&mut state, id, Failure::Rejected);
+        assert_eq!(balances(&state, STAKER), (0, 490));
+        assert_eq!(balances(&state, RECIPIENT), (0, 500));
+        assert!(state.staker_locks.is_empty());
+    }
+
+    #[test]
+    fn otbtc_export_deducts_the_amount_and_the_fee() {
+        let mut state = state_with_staker(0, 1_000);
+        let id = create(&mut state, OperationKind::ExportOtbtc, 500, 0, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (0, 490));
+        assert_eq!(state.operations[&id].status, OperationStatus::Completed);
+    }
+
+    #[test]
+    fn rejected_distribution_leaves_the_share_in_the_pool() {
+        let mut state = state_with_staker(0, 0);
+        state.total_ckbtc_in_pool = 1_000;
+        let id = create(&mut state, OperationKind::DistributeRewards, 100, 0, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (0, 100));
+        assert_eq!(state.total_ckbtc_in_pool, 1_000);
+
+        // The reward of a failed distribution is not counted in the pool.
+        let id = create(&mut state, OperationKind::DistributeRewards, 100, 0, None);
+        fail(&mut state, id, Failure::Rejected);
+        assert_eq!(balances(&state, STAKER), (0, 100));
+        assert_eq!(state.total_ckbtc_in_pool, 900);
+        assert!(is_failed(&state, id));
+    }
+
+    #[test]
+    fn bridge_locks_otbtc_and_records_the_voucher() {
+        let mut state = state_with_staker(0, 1_000);
+        let destination = Some(Destination::Bridge {
+            erc20_contract: "0x".to_string() + &"ab".repeat(20),
+            chain_id: 1,
+        });
+        let id = create(&mut state, OperationKind::BridgeOtbtc, 500, 0, destination);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (0, 490));
+        assert_eq!(state.bridged_otbtc, 500);
+        let voucher = &state.bridge_vouchers[&id];
+        assert_eq!((voucher.nonce, voucher.amount), (id, 500));
+        assert_eq!(voucher.message.len(), 5 * 32);
+    }
+
+    #[test]
+    fn redemption_credits_otbtc_or_is_left_to_an_operator() {
+        let mut state = state_with_staker(0, 0);
+        let id = create(&mut state, OperationKind::RedeemOtbtc, 500, 0, None);
+        complete(&mut state, id);
+        assert_eq!(balances(&state, STAKER), (0, 490));
+
+        let id = create(&mut state, OperationKind::RedeemOtbtc, 500, 0, None);
+        fail(&mut state, id, Failure::Rejected);
+        assert!(state.operations[&id].needs_operator);
+        assert_eq!(balances(&state, STAKER), (0, 490));
+    }
+}
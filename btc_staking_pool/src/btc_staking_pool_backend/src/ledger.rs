@@ -0,0 +1,140 @@
+use crate::icrc::{Account, TransferArg, TransferError, TransferFromArgs, TransferFromError};
+use candid::{Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use serde_bytes::ByteBuf;
+
+const NONCE_BITS: u32 = 56;
+
+/// The action of the pool encoded in the memo of its ledger transfers.
+#[derive(Clone, Copy, Debug)]
+pub enum MemoAction {
+    Stake = 1,
+    Unstake = 2,
+    UnlockTokens = 3,
+    WithdrawBtc = 4,
+    DistributeRewards = 5,
+    WithdrawTreasury = 6,
+    TransferOtbtc = 7,
+    ExportOtbtc = 8,
+    ImportOtbtc = 9,
+    DepositCkbtc = 10,
+    WithdrawCkbtc = 11,
+    BridgeOtbtc = 12,
+    RedeemOtbtc = 13,
+}
+
+/// The memo of the ledger transfers of an operation, with the action in the highest byte and
+/// the nonce in the lower 7 bytes.
+///
+/// The nonce is the staker nonce consumed by a signed operation, or an operation id otherwise.
+pub fn memo(action: MemoAction, nonce: u64) -> u64 {
+    ((action as u64) << NONCE_BITS) | (nonce & ((1 << NONCE_BITS) - 1))
+}
+
+/// The memo with the same action and another nonce.
+pub fn with_nonce(memo: u64, nonce: u64) -> u64 {
+    (memo & !((1 << NONCE_BITS) - 1)) | (nonce & ((1 << NONCE_BITS) - 1))
+}
+
+/// The ICRC-1 memo of a transfer, which is the big-endian encoding of the memo.
+pub fn memo_bytes(memo: u64) -> Option<ByteBuf> {
+    Some(ByteBuf::from(memo.to_be_bytes().to_vec()))
+}
+
+/// Transfer tokens on an ICRC-1 ledger, treating a transfer rejected as the duplicate of an
+/// earlier one as successful.
+///
+/// A transfer with `created_at_time` set is deduplicated by the ledger, so a transfer whose
+/// outcome is unknown can be retried safely with exactly the same arguments.
+pub async fn transfer(
+    ledger: Principal,
+    args: TransferArg,
+) -> CallResult<Result<Nat, TransferError>> {
+    let (result,): (Result<Nat, TransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await?;
+    Ok(match result {
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        result => result,
+    })
+}
+
+/// Transfer tokens approved by their owner on an ICRC-2 ledger, treating a transfer rejected as
+/// the duplicate of an earlier one as successful.
+pub async fn transfer_from(
+    ledger: Principal,
+    args: TransferFromArgs,
+) -> CallResult<Result<Nat, TransferFromError>> {
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await?;
+    Ok(match result {
+        Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        result => result,
+    })
+}
+
+/// The transfer fee of an ICRC-1 ledger.
+pub async fn fee(ledger: Principal) -> CallResult<u64> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ()).await?;
+    Ok(u64::try_from(fee.0).unwrap_or(u64::MAX))
+}
+
+/// The balance of an account on an ICRC-1 ledger.
+pub async fn balance_of(ledger: Principal, account: Account) -> CallResult<u64> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,)).await?;
+    // The supply of BTC fits into 64 bits.
+    Ok(u64::try_from(balance.0).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE_MASK: u64 = (1 << NONCE_BITS) - 1;
+
+    #[test]
+    fn memo_encodes_action_and_nonce() {
+        for action in [MemoAction::Stake, MemoAction::RedeemOtbtc] {
+            for nonce in [0, 1, 0x1234_5678, NONCE_MASK] {
+                let memo = memo(action, nonce);
+                assert_eq!(memo >> NONCE_BITS, action as u64);
+                assert_eq!(memo & NONCE_MASK, nonce);
+            }
+        }
+        assert_eq!(memo(MemoAction::WithdrawBtc, 7), 0x0400_0000_0000_0007);
+        assert_eq!(
+            memo_bytes(memo(MemoAction::WithdrawBtc, 7))
+                .unwrap()
+                .as_slice(),
+            &[4, 0, 0, 0, 0, 0, 0, 7]
+        );
+    }
+
+    #[test]
+    fn memo_truncates_nonce_to_56_bits() {
+        assert_eq!(
+            memo(MemoAction::Stake, u64::MAX),
+            memo(MemoAction::Stake, NONCE_MASK)
+        );
+        assert_eq!(
+            memo(MemoAction::Stake, 1 << NONCE_BITS),
+            memo(MemoAction::Stake, 0)
+        );
+        assert_eq!(memo(MemoAction::Stake, u64::MAX) >> NONCE_BITS, 1);
+    }
+
+    #[test]
+    fn with_nonce_keeps_action() {
+        let memo = memo(MemoAction::TransferOtbtc, 42);
+        assert_eq!(
+            with_nonce(memo, 43),
+            super::memo(MemoAction::TransferOtbtc, 43)
+        );
+        assert_eq!(with_nonce(with_nonce(memo, 43), 42), memo);
+        // The nonce is truncated as in `memo`.
+        assert_eq!(with_nonce(memo, u64::MAX) >> NONCE_BITS, 7);
+        assert_eq!(
+            with_nonce(memo, (1 << NONCE_BITS) | 5),
+            super::memo(MemoAction::TransferOtbtc, 5)
+        );
+    }
+}
@@ -1,33 +1,93 @@
 extern crate alloc;
 
+mod address;
+mod attestation;
+mod bridge;
+mod btc_signature;
+mod certification;
+mod cycles;
+mod eip1271;
 mod errors;
+mod eth_signer;
+mod http;
+mod icrc;
+mod journal;
+mod ledger;
+mod log;
+mod metrics;
+mod scanner;
+mod siwe;
 mod state;
 mod types;
 
 use alloc::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     vec::Vec,
 };
-use core::fmt::Error;
+use btc_signature::BitcoinAddress;
+use candid::Principal;
+use core::{fmt::Error, time::Duration};
 use errors::{
-    GetBtcDepositAddressError, StakeError, UnlockTokensInQueueError, UnstakeError,
-    UpdateBalanceError, VerifySignatureError, WithdrawBtcError,
+    AttestationError, BridgeOtbtcError, BridgeVoucherError, ConfigError, DepositCkbtcError,
+    DistributeRewardsError, ExportOtbtcError, GetBtcDepositAddressError, ImportOtbtcError,
+    RedeemFromEthError, ResolveOperationError, SiweError, StakeError, SyncMinterInfoError,
+    TransferOtbtcError, UnlockTokensInQueueError, UnstakeError, UpdateBalanceError,
+    VerifySignatureError, WithdrawBtcError, WithdrawCkbtcError, WithdrawTreasuryError,
 };
-use ic_cdk::{init, update};
-use ic_ledger_types::{AccountIdentifier, Memo, Subaccount, Tokens};
+use ic_btc_interface::Network;
+use ic_cdk::{
+    api::management_canister::ecdsa::{
+        ecdsa_public_key, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    },
+    init, post_upgrade, pre_upgrade, query, update,
+};
+use ic_ledger_types::Subaccount;
+use icrc::{Account, TransferFromArgs};
+use journal::{OperationRequest, Outcome, PoolTransferOutcome};
+use ledger::MemoAction;
 use libsecp256k1::{Message, RecoveryId, Signature};
+use log::LogEntry;
+use serde_bytes::ByteBuf;
 use sha3::Digest;
-use state::{BtcStakingPoolState, Staker, UnstakeRequest};
+use state::{
+    AttestationConfig, BridgeConfig, BridgeVoucher, BtcStakingPoolState, Deposit, Destination,
+    EcdsaPublicKey, Eip1271Config, MinimumAmounts, Operation, OperationKind, OperationStatus,
+    OperationStep, PoolTransfer, PoolTransferKind, ProtocolFeeConfig, SiweConfig, SiweLogin,
+    SiweSession, StakeAttestation, Staker, StakerLock, StakingCaps,
+};
 use types::{
-    GetBtcAddressArgs, InitArgs, StakeArgs, UnstakeArgs, UpdateBalanceArgs, UpdateBalanceResponse,
-    UtxoStatus, WithdrawBtcArgs,
+    BridgeOtbtcArgs, BridgeOtbtcResponse, BurnProof, CyclesBalance, DepositCkbtcArgs,
+    DistributeRewardsResponse, ExportOtbtcArgs, ExportOtbtcResponse, GetBtcAddressArgs,
+    GetDepositsArgs, GetLogsArgs, GetStakerResponse, HttpRequest, HttpResponse, ImportOtbtcArgs,
+    InitArgs, MinterInfo, RedeemFromEthResponse, StakeArgs, StakeResponse, StakerBalances,
+    TransferOtbtcArgs, TransferOtbtcResponse, UnlockTokensResponse, UnstakeArgs, UnstakeResponse,
+    UpdateBalanceArgs, UpdateBalanceResponse, UtxoStatus, WithdrawBtcArgs, WithdrawBtcResponse,
+    WithdrawCkbtcArgs, WithdrawCkbtcResponse, WithdrawTreasuryArgs, WithdrawTreasuryResponse,
 };
 
 const DEFAULT_UNBONDING_PERIOD: u64 = 60 * 60 * 24 * 14 * 1000000; // 2 weeks, in nano seconds
+const DEFAULT_DEPOSIT_SCAN_IDLE_PERIOD: u64 = 60 * 60 * 24 * 7 * 1000000000; // 1 week, in nano seconds
+const MAX_DEPOSITS_PAGE_SIZE: u64 = 100;
+const BPS_DENOMINATOR: u16 = 10_000;
+const MINTER_INFO_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+const DEFAULT_CYCLES_LOW_WATER_MARK: u128 = 1_000_000_000_000; // 1T cycles
+const SIWE_LOGIN_DEADLINE: u64 = 5 * 60 * 1_000_000_000; // 5 minutes, in nano seconds
+const ATTESTATION_REUSE_PERIOD: u64 = 10 * 60 * 1_000_000_000; // 10 minutes, in nano seconds
+const EIP1271_CHECK_INTERVAL: u64 = 60 * 1_000_000_000; // 1 minute, in nano seconds
+const SUBACCOUNT_DOMAIN: &[u8] = b"btc_staking_pool:";
+const PRINCIPAL_SUBACCOUNT_TAG: &[u8] = b"principal:";
+const BTC_SUBACCOUNT_TAG: &[u8] = b"btc:";
 
 #[init]
 fn init(init_args: InitArgs) {
-    state::replace_state(BtcStakingPoolState {
+    state::replace_state(initial_state(init_args));
+    state::read_state(certification::certify_all);
+    start_timers();
+}
+
+/// The state of a new canister.
+fn initial_state(init_args: InitArgs) -> BtcStakingPoolState {
+    BtcStakingPoolState {
         ckbtc_minting_account: init_args.ckbtc_minting_account,
         ckbtc_ledger_account: init_args.ckbtc_ledger_account,
         otbtc_ledger_account: init_args.otbtc_ledger_account,
@@ -35,17 +95,191 @@ fn init(init_args: InitArgs) {
         unstaking_queue: VecDeque::new(),
         total_ckbtc_in_pool: 0,
         unbonding_period: DEFAULT_UNBONDING_PERIOD,
+        deposit_scans: BTreeMap::new(),
+        deposit_scan_idle_period: init_args
+            .deposit_scan_idle_period
+            .unwrap_or(DEFAULT_DEPOSIT_SCAN_IDLE_PERIOD),
+        btc_network: init_args.btc_network,
+        ckbtc_minter_ecdsa_key_name: init_args.ckbtc_minter_ecdsa_key_name,
+        ckbtc_minter_public_key: None,
+        ckbtc_transfer_fee: init_args.ckbtc_transfer_fee.unwrap_or_default(),
+        otbtc_transfer_fee: init_args.otbtc_transfer_fee.unwrap_or_default(),
+        protocol_fee_config: ProtocolFeeConfig::default(),
+        treasury_balance: 0,
+        minimum_amounts: MinimumAmounts::default(),
+        retrieve_btc_min_amount: 0,
+        staking_caps: StakingCaps::default(),
+        staker_allowlist: BTreeSet::new(),
+        operations: BTreeMap::new(),
+        next_operation_id: 0,
+        pool_transfers: BTreeMap::new(),
+        staker_locks: BTreeMap::new(),
+        operation_metrics: BTreeMap::new(),
+        cycles_low_water_mark: DEFAULT_CYCLES_LOW_WATER_MARK,
+        cycles_low: false,
+        siwe_config: None,
+        siwe_logins: BTreeMap::new(),
+        siwe_sessions: BTreeMap::new(),
+        eip1271_config: None,
+        eip1271_last_checks: BTreeMap::new(),
+        eth_signer_key_name: None,
+        eth_signer_public_key: None,
+        attestation_config: None,
+        next_attestation_nonce: 0,
+        attestations: BTreeMap::new(),
+        bridge_config: None,
+        bridge_vouchers: BTreeMap::new(),
+        bridged_otbtc: 0,
+        redeemed_burns: BTreeSet::new(),
+    }
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    state::save_to_stable_memory();
+}
+
+/// Restore the state, including the journal of the unfinished operations, and restart the
+/// timers, which do not survive an upgrade.
+#[post_upgrade]
+fn post_upgrade() {
+    state::restore_from_stable_memory();
+    state::mutate_state(|state| {
+        // A balance sync holds the lock of a staker across a call whose callback is dropped by
+        // the upgrade, while the operations are resumed by the recovery timer.
+        state
+            .staker_locks
+            .retain(|_, lock| *lock != StakerLock::BalanceSync);
+        certification::certify_all(state);
+    });
+    start_timers();
+}
+
+fn start_timers() {
+    scanner::start_timer();
+    journal::start_timer();
+    http::start_timer();
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(sync_minter_info_periodically())
+    });
+    ic_cdk_timers::set_timer_interval(MINTER_INFO_SYNC_INTERVAL, || {
+        ic_cdk::spawn(sync_minter_info_periodically())
     });
 }
 
+/// Get the BTC deposit address of a staker.
+///
+/// As anyone can call this for any address, the address is registered for automatic deposit
+/// detection, and the staker record caching it is created, within the bounds of
+/// `scanner::register_unauthenticated_address`, while `get_btc_deposit_address_for_caller`
+/// always does both.
 #[update]
 async fn get_btc_deposit_address(eth_address: String) -> Result<String, GetBtcDepositAddressError> {
+    let eth_address = canonical_staker_address(&eth_address)
+        .ok_or(GetBtcDepositAddressError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
+        .map_err(|_| GetBtcDepositAddressError::InvalidEthereumAddress)?;
+    let address = btc_deposit_address(&eth_address).await?;
+    if scanner::register_unauthenticated_address(&eth_address) {
+        record_btc_deposit_address(&eth_address, subaccount, &address);
+    }
+    Ok(address)
+}
+
+/// Get the BTC deposit address of the account of the caller, and register it for automatic
+/// deposit detection.
+#[update]
+async fn get_btc_deposit_address_for_caller() -> Result<String, GetBtcDepositAddressError> {
+    let eth_address = caller_account().ok_or(GetBtcDepositAddressError::AnonymousCaller)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
+        .map_err(|_| GetBtcDepositAddressError::InvalidEthereumAddress)?;
+    let address = btc_deposit_address(&eth_address).await?;
+    record_btc_deposit_address(&eth_address, subaccount, &address);
+    scanner::register_address(&eth_address);
+    Ok(address)
+}
+
+/// Cache the BTC deposit address of a staker in its record, which is created if needed.
+fn record_btc_deposit_address(eth_address: &str, subaccount: Subaccount, address: &str) {
+    state::mutate_state(|state| {
+        state
+            .stakers_map
+            .entry(eth_address.to_string())
+            .or_insert_with(|| Staker::new(eth_address.to_string(), subaccount))
+            .btc_deposit_address = Some(address.to_string());
+        certification::certify_staker(state, eth_address);
+    });
+}
+
+/// Get the BTC deposit address of a staker, cached in the staker record if there is one.
+async fn btc_deposit_address(eth_address: &str) -> Result<String, GetBtcDepositAddressError> {
+    let subaccount = convert_staker_address_to_subaccount(eth_address)
+        .map_err(|_| GetBtcDepositAddressError::InvalidEthereumAddress)?;
+    if let Some(address) = state::read_state(|state| {
+        state
+            .stakers_map
+            .get(eth_address)
+            .and_then(|staker| staker.btc_deposit_address.clone())
+    }) {
+        return Ok(address);
+    }
+    let address = resolve_btc_deposit_address(subaccount).await?;
+    // The address of a subaccount never changes, so cache it in the staker record.
+    state::mutate_state(|state| {
+        if let Some(staker) = state.stakers_map.get_mut(eth_address) {
+            staker.btc_deposit_address = Some(address.clone());
+            http::staker_changed(eth_address);
+        }
+    });
+    Ok(address)
+}
+
+/// Get the BTC deposit address of a staker without calling the ckBTC minter.
+///
+/// Returns the cached address, or the address derived from the ECDSA public key of the ckBTC
+/// minter if it is known, or `None` if `get_btc_deposit_address` needs to be called first.
+#[query]
+fn get_cached_btc_deposit_address(eth_address: String) -> Option<String> {
+    let eth_address = canonical_staker_address(&eth_address)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address).ok()?;
+    state::read_state(|state| {
+        state
+            .stakers_map
+            .get(&eth_address)
+            .and_then(|staker| staker.btc_deposit_address.clone())
+    })
+    .or_else(|| derive_btc_deposit_address(&subaccount))
+}
+
+/// Get the BTC deposit address of a subaccount, derived locally if possible,
+/// or by calling the ckBTC minter otherwise.
+async fn resolve_btc_deposit_address(
+    subaccount: Subaccount,
+) -> Result<String, GetBtcDepositAddressError> {
+    if let Some(address) = derive_btc_deposit_address(&subaccount) {
+        return Ok(address);
+    }
+    let address = request_btc_deposit_address(subaccount).await?;
+    let key_name = state::read_state(|state| match state.ckbtc_minter_public_key {
+        None if state.btc_network.is_some() => state.ckbtc_minter_ecdsa_key_name.clone(),
+        _ => None,
+    });
+    if let Some(key_name) = key_name {
+        // The minter is called again next time if the public key cannot be fetched.
+        if let Err(e) = fetch_ckbtc_minter_public_key(key_name, &subaccount, &address).await {
+            log::warn("fetch_ckbtc_minter_public_key", e);
+        }
+    }
+    Ok(address)
+}
+
+/// Get the BTC deposit address of a subaccount from the ckBTC minter.
+async fn request_btc_deposit_address(
+    subaccount: Subaccount,
+) -> Result<String, GetBtcDepositAddressError> {
     let args = GetBtcAddressArgs {
         owner: Some(ic_cdk::id()),
-        subaccount: Some(
-            convert_eth_address_to_subaccount(&eth_address)
-                .map_err(|_| GetBtcDepositAddressError::InvalidEthereumAddress)?,
-        ),
+        subaccount: Some(subaccount),
     };
     let ckbtc_minting_account = state::read_state(|state| state.ckbtc_minting_account);
     let (address,) = ic_cdk::call(ckbtc_minting_account, "get_btc_address", (args,))
@@ -59,6 +293,73 @@ async fn get_btc_deposit_address(eth_address: String) -> Result<String, GetBtcDe
     Ok(address)
 }
 
+/// Fetch the ECDSA public key of the ckBTC minter from the management canister.
+///
+/// The key is only kept for deriving the addresses locally if the address it derives for a
+/// subaccount is the one returned by the minter. Otherwise the key name is cleared, so that
+/// the addresses are always requested from the minter.
+async fn fetch_ckbtc_minter_public_key(
+    key_name: String,
+    subaccount: &Subaccount,
+    minter_address: &str,
+) -> Result<(), String> {
+    let ckbtc_minting_account = state::read_state(|state| state.ckbtc_minting_account);
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: Some(ckbtc_minting_account),
+        derivation_path: vec![],
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: key_name,
+        },
+    })
+    .await
+    .map_err(|e| format!("failed to call management canister: {:?}", e))?;
+    let public_key = EcdsaPublicKey {
+        public_key: response.public_key,
+        chain_code: response.chain_code,
+    };
+    let network = state::read_state(|state| state.btc_network)
+        .ok_or_else(|| "the Bitcoin network is not configured".to_string())?;
+    let derived_address =
+        address::derive_btc_deposit_address(&public_key, network, &ic_cdk::id(), subaccount)?;
+    if derived_address != minter_address {
+        state::mutate_state(|state| state.ckbtc_minter_ecdsa_key_name = None);
+        return Err(format!(
+            "the derived address {} is not the address {} of the ckBTC minter, the addresses are \
+             requested from the minter",
+            derived_address, minter_address
+        ));
+    }
+    state::mutate_state(|state| state.ckbtc_minter_public_key = Some(public_key));
+    Ok(())
+}
+
+/// Derive the BTC deposit address of a subaccount from the ECDSA public key of the ckBTC minter,
+/// if the key and the Bitcoin network are known.
+fn derive_btc_deposit_address(subaccount: &Subaccount) -> Option<String> {
+    state::read_state(|state| {
+        let minter_public_key = state.ckbtc_minter_public_key.as_ref()?;
+        let network = state.btc_network?;
+        address::derive_btc_deposit_address(minter_public_key, network, &ic_cdk::id(), subaccount)
+            .ok()
+    })
+}
+
+/// The current time, in nano seconds.
+#[cfg(target_arch = "wasm32")]
+fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+/// The system time, in nano seconds, outside of a canister, such as in the unit tests.
+#[cfg(not(target_arch = "wasm32"))]
+fn time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("the system time is before the epoch")
+        .as_nanos() as u64
+}
+
 fn keccak256(input: &[u8]) -> [u8; 32] {
     // Create a new Keccak-256 hasher
     let mut hasher = sha3::Keccak256::new();
@@ -70,151 +371,544 @@ fn keccak256(input: &[u8]) -> [u8; 32] {
     result.into()
 }
 
+/// The canonical form of the address identifying a staker, which is the key of its record: an
+/// Ethereum address in lower case without the `0x` prefix, a Bitcoin address, or the textual
+/// representation of a principal.
+///
+/// Every endpoint taking the address of a staker converts it first, so that the forms of the
+/// same address cannot have distinct records sharing one subaccount.
+fn canonical_staker_address(address: &str) -> Option<String> {
+    if let Ok(principal) = Principal::from_text(address) {
+        return Some(principal.to_text());
+    }
+    // Bech32 addresses are only parsed in lower case, and Base58 addresses are case sensitive.
+    if parse_bitcoin_address(address).is_some() {
+        return Some(address.to_string());
+    }
+    siwe::normalize_eth_address(address)
+}
+
+/// Parse a Bitcoin address of the network of the ckBTC minter, or of the main network if it is
+/// not configured, so that the addresses of another network cannot identify a staker.
+fn parse_bitcoin_address(address: &str) -> Option<BitcoinAddress> {
+    let network = state::read_state(|state| state.btc_network).unwrap_or(Network::Mainnet);
+    btc_signature::parse_address(address, network)
+}
+
+/// Convert the address identifying either an Ethereum address, a Bitcoin address or
+/// a principal, to the subaccount of the staker.
+///
+/// The subaccount of an Ethereum address is the hash of its 20 bytes, and the subaccounts of a
+/// Bitcoin address and of a principal are the hashes of `tagged_preimage`, which is longer than
+/// 20 bytes, so that the schemes cannot collide.
+fn convert_staker_address_to_subaccount(address: &str) -> Result<Subaccount, Error> {
+    if let Ok(principal) = Principal::from_text(address) {
+        let preimage = tagged_preimage(PRINCIPAL_SUBACCOUNT_TAG, principal.as_slice());
+        return Ok(Subaccount(keccak256(&preimage)));
+    }
+    if parse_bitcoin_address(address).is_some() {
+        let preimage = tagged_preimage(BTC_SUBACCOUNT_TAG, address.as_bytes());
+        return Ok(Subaccount(keccak256(&preimage)));
+    }
+    convert_eth_address_to_subaccount(address)
+}
+
+/// The preimage of the subaccount of a staker which is not identified by an Ethereum address:
+/// the domain `SUBACCOUNT_DOMAIN`, the tag of the scheme, and the payload prefixed with its
+/// length.
+fn tagged_preimage(tag: &[u8], payload: &[u8]) -> Vec<u8> {
+    let length = u8::try_from(payload.len()).expect("payload too long, should not happen");
+    [SUBACCOUNT_DOMAIN, tag, &[length], payload].concat()
+}
+
 /// Convert a string (considered to be an Ethereum address) to a subaccount (with 32 bytes).
 fn convert_eth_address_to_subaccount(eth_address: &str) -> Result<Subaccount, Error> {
-    if eth_address.len() != 20 {
+    Ok(Subaccount(keccak256(&eth_address_preimage(eth_address)?)))
+}
+
+/// The preimage of the subaccount of an Ethereum address, which is its 20 bytes.
+fn eth_address_preimage(eth_address: &str) -> Result<Vec<u8>, Error> {
+    // Only the canonical form is accepted, see `canonical_staker_address`.
+    if eth_address.len() != 40 || eth_address != eth_address.to_lowercase() {
         return Err(Error::default());
     }
-    let address_bytes = hex::decode(eth_address).map_err(|_| Error::default())?;
-    Ok(Subaccount(keccak256(&address_bytes)))
+    hex::decode(eth_address).map_err(|_| Error::default())
 }
 
 #[update]
 async fn update_balance(eth_address: String) -> Result<u64, UpdateBalanceError> {
+    let eth_address =
+        canonical_staker_address(&eth_address).ok_or(UpdateBalanceError::InvalidEthereumAddress)?;
+    process_balance_update(&eth_address).await
+}
+
+/// Update the balance of the principal account of the caller.
+#[update]
+async fn update_balance_for_caller() -> Result<u64, UpdateBalanceError> {
+    let eth_address = caller_account().ok_or(UpdateBalanceError::AnonymousCaller)?;
+    process_balance_update(&eth_address).await
+}
+
+/// Update the balance of a staker, both for the `update_balance` endpoint and for the
+/// automatic deposit detection.
+async fn process_balance_update(eth_address: &str) -> Result<u64, UpdateBalanceError> {
+    let subaccount = convert_staker_address_to_subaccount(eth_address)
+        .map_err(|_| UpdateBalanceError::InvalidEthereumAddress)?;
     let args = UpdateBalanceArgs {
         owner: Some(ic_cdk::id()),
-        subaccount: Some(
-            convert_eth_address_to_subaccount(&eth_address)
-                .map_err(|_| UpdateBalanceError::InvalidEthereumAddress)?,
-        ),
+        subaccount: Some(subaccount.clone()),
     };
     // Update balance by calling the ckBTC minter canister.
     let ckbtc_minting_account = state::read_state(|state| state.ckbtc_minting_account);
-    let (res,): (UpdateBalanceResponse,) =
-        ic_cdk::call(ckbtc_minting_account, "update_balance", (args.clone(),))
-            .await
-            .map_err(|e| {
-                UpdateBalanceError::CkbtcMinterError(format!(
+    let minter_result: Result<(UpdateBalanceResponse,), _> =
+        ic_cdk::call(ckbtc_minting_account, "update_balance", (args,)).await;
+    let log_context = format!("update_balance of {}", eth_address);
+    // The minted ckBTC is credited by the ledger balance of the subaccount rather than by the
+    // minter response, as the mint may also have been triggered by someone else.
+    let credited = credit_ckbtc_deposits(eth_address, subaccount)
+        .await
+        .map_err(|e| {
+            if let UpdateBalanceError::CkbtcLedgerError(message) = &e {
+                log::warn(&log_context, message.clone());
+            }
+            e
+        })?;
+    if credited > 0 {
+        log::info(&log_context, format!("credited {} ckBTC", credited));
+    }
+    match minter_result {
+        Ok((res,)) => record_deposits(eth_address, res.0),
+        Err(e) => {
+            log::warn(
+                &log_context,
+                format!("failed to call ckBTC minter: {:?}", e),
+            );
+            if credited == 0 {
+                return Err(UpdateBalanceError::CkbtcMinterError(format!(
                     "failed to call ckBTC minter: {:?}",
                     e
-                ))
-            })?;
-    let mut balance = 0;
-    for status in res.0 {
-        match status {
-            UtxoStatus::Minted { minted_amount, .. } => {
-                balance += minted_amount;
+                )));
             }
-            _ => {}
         }
     }
+    //
+    Ok(credited)
+}
+
+/// Credit the staker with the ckBTC in its subaccount which is not accounted for by the pool yet.
+///
+/// The pool keeps exactly `ckbtc_balance` of ckBTC in the subaccount of a staker, so anything
+/// above that arrived from outside: mints triggered by anyone calling `update_balance` of the
+/// ckBTC minter for this subaccount, or plain ckBTC transfers to it.
+///
+/// The staker is locked meanwhile, as the comparison is only valid if no operation moves ckBTC
+/// in or out of the subaccount between reading the ledger balance and crediting the staker.
+async fn credit_ckbtc_deposits(
+    eth_address: &str,
+    subaccount: Subaccount,
+) -> Result<u64, UpdateBalanceError> {
+    let _guard =
+        journal::lock_for_balance_sync(eth_address).ok_or(UpdateBalanceError::StakerBusy)?;
+    credit_unaccounted_ckbtc(eth_address, subaccount)
+        .await
+        .map_err(UpdateBalanceError::CkbtcLedgerError)
+}
+
+/// Credit the staker with the ckBTC in its subaccount which is not accounted for by the pool
+/// yet, see `credit_ckbtc_deposits`. The caller must hold the lock of the staker.
+async fn credit_unaccounted_ckbtc(
+    eth_address: &str,
+    subaccount: Subaccount,
+) -> Result<u64, String> {
+    let ckbtc_ledger_account = state::read_state(|state| state.ckbtc_ledger_account);
+    let balance = ledger::balance_of(
+        ckbtc_ledger_account,
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(subaccount),
+        },
+    )
+    .await
+    .map_err(|e| format!("failed to call ckBTC ledger: {:?}", e))?;
     // Update the state.
-    state::mutate_state(|state| {
+    let credited = state::mutate_state(|state| {
         let staker = state
             .stakers_map
-            .entry(eth_address.clone())
-            .or_insert_with(|| Staker {
-                eth_address: eth_address.clone(),
-                subaccount: args.subaccount.clone().unwrap(),
-                tx_nonce: 0,
-                ckbtc_balance: 0,
-                otbtc_balance: 0,
-            });
-        staker.ckbtc_balance += balance;
+            .entry(eth_address.to_string())
+            .or_insert_with(|| Staker::new(eth_address.to_string(), subaccount));
+        let credited = balance.saturating_sub(staker.ckbtc_balance);
+        staker.ckbtc_balance += credited;
+        certification::certify_staker(state, eth_address);
+        credited
     });
-    //
-    Ok(balance)
+    Ok(credited)
+}
+
+/// Record the UTXOs minted by the ckBTC minter in the deposit history of the staker.
+fn record_deposits(eth_address: &str, statuses: Vec<UtxoStatus>) {
+    let now = time();
+    state::mutate_state(|state| {
+        let Some(staker) = state.stakers_map.get_mut(eth_address) else {
+            return;
+        };
+        for status in statuses {
+            if let UtxoStatus::Minted {
+                block_index,
+                minted_amount,
+                utxo,
+            } = status
+            {
+                let txid = utxo.outpoint.txid.to_string();
+                let vout = utxo.outpoint.vout;
+                // The minter reports a minted UTXO again if it is asked for it again.
+                if staker
+                    .deposits
+                    .iter()
+                    .any(|deposit| deposit.txid == txid && deposit.vout == vout)
+                {
+                    continue;
+                }
+                staker.deposits.push(Deposit {
+                    txid,
+                    vout,
+                    value: utxo.value,
+                    minted_amount,
+                    block_index,
+                    time: now,
+                });
+            }
+        }
+    });
+}
+
+/// Get the deposit history of a staker, oldest first.
+#[query]
+fn get_deposits(args: GetDepositsArgs) -> Vec<Deposit> {
+    let Some(eth_address) = canonical_staker_address(&args.eth_address) else {
+        return vec![];
+    };
+    state::read_state(|state| {
+        state
+            .stakers_map
+            .get(&eth_address)
+            .map(|staker| {
+                staker
+                    .deposits
+                    .iter()
+                    .skip(args.offset as usize)
+                    .take(args.limit.min(MAX_DEPOSITS_PAGE_SIZE) as usize)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+#[update]
+async fn stake(args: StakeArgs) -> Result<StakeResponse, StakeError> {
+    stake_for(
+        args.eth_address,
+        args.amount,
+        Authorization::Signature(&args.signature),
+    )
+    .await
 }
 
+/// Stake for the principal account of the caller.
 #[update]
-async fn stake(args: StakeArgs) -> Result<(), StakeError> {
-    let subaccount = convert_eth_address_to_subaccount(&args.eth_address)
+async fn stake_for_caller(amount: u64) -> Result<StakeResponse, StakeError> {
+    let eth_address = caller_account().ok_or(StakeError::AnonymousCaller)?;
+    stake_for(eth_address, amount, Authorization::Caller).await
+}
+
+async fn stake_for(
+    eth_address: String,
+    amount: u64,
+    authorization: Authorization<'_>,
+) -> Result<StakeResponse, StakeError> {
+    let eth_address =
+        canonical_staker_address(&eth_address).ok_or(StakeError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
         .map_err(|_| StakeError::InvalidEthereumAddress)?;
-    let mut staker = state::take_state(|state| {
+    let min = state::read_state(|state| effective_minimum_amounts(state).stake);
+    if amount < min {
+        return Err(StakeError::AmountTooLow { min });
+    }
+    let staker = state::read_state(|state| {
         state
             .stakers_map
-            .get(&args.eth_address)
+            .get(&eth_address)
             .ok_or(StakeError::LackOfStakerRecord)
             .cloned()
     })?;
-    if staker.ckbtc_balance < args.amount {
+    // The staker pays the fee of moving ckBTC out of its subaccount.
+    let fee = state::read_state(|state| state.ckbtc_transfer_fee);
+    if staker.ckbtc_balance < amount.saturating_add(fee) {
         return Err(StakeError::NotEnoughCkbtcBalance);
     }
-    // Verify the signature.
-    if verify_signature(&staker, "stake", args.amount, &args.signature).is_err() {
+    state::read_state(|state| check_staking_caps(state, &staker, amount))?;
+    if authorize(&staker, "stake", amount, None, &authorization)
+        .await
+        .is_err()
+    {
         return Err(StakeError::InvalidSignature);
     }
-    // Transfer ckBTC tokens to the main account.
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: Tokens::from_e8s(args.amount),
-        fee: Tokens::from_e8s(10_000),
-        // The subaccount of the account identifier that will be used to withdraw tokens and send them
-        // to another account identifier. If set to None then the default subaccount will be used.
-        // See the [Ledger doc](https://internetcomputer.org/docs/current/developer-docs/integrations/ledger/#accounts).
-        from_subaccount: Some(subaccount.clone()),
-        to: AccountIdentifier::new(&ic_cdk::id(), &Subaccount([0u8; 32])),
-        created_at_time: None,
+    // Record the operation, which transfers ckBTC tokens to the main account and then mints
+    // otBTC tokens for the staker.
+    let operation_id = state::mutate_state(|state| {
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(StakeError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(StakeError::InvalidSignature);
+        }
+        // Other stakes may have been recorded while the signature was verified.
+        check_staking_caps(state, &state.stakers_map[&eth_address], amount)?;
+        let nonce = consume_nonce(state, &eth_address);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::Stake,
+                eth_address: &eth_address,
+                subaccount,
+                amount,
+                fee,
+                protocol_fee: 0,
+                destination: None,
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(StakeResponse {
+            operation_id,
+            amount: op.amount,
+            fee: op.fee,
+        }),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(StakeError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
+}
+
+/// Increase the nonce of a staker, so that the signature of an operation cannot be used again.
+///
+/// Returns the consumed nonce.
+fn consume_nonce(state: &mut BtcStakingPoolState, eth_address: &str) -> u64 {
+    let staker = state
+        .stakers_map
+        .get_mut(eth_address)
+        .expect("staker not found, should not happen");
+    staker.tx_nonce += 1;
+    http::staker_changed(eth_address);
+    staker.tx_nonce - 1
+}
+
+/// Check that staking the amount stays within the staking caps.
+///
+/// The amounts of the stakes in progress whose ckBTC is not in the pool yet are counted
+/// against the pool cap, so that concurrent stakes cannot exceed it together.
+fn check_staking_caps(
+    state: &BtcStakingPoolState,
+    staker: &Staker,
+    amount: u64,
+) -> Result<(), StakeError> {
+    if state.staking_caps.allowlist_enabled && !state.staker_allowlist.contains(&staker.eth_address)
+    {
+        return Err(StakeError::NotInAllowlist);
+    }
+    if let Some(max) = state.staking_caps.max_total_ckbtc_in_pool {
+        let pending_stakes: u64 = state
+            .operations
+            .values()
+            .filter(|op| {
+                op.kind == OperationKind::Stake
+                    && op.status == OperationStatus::InProgress
+                    && op.step == OperationStep::TransferCkbtcToPool
+            })
+            .map(|op| op.amount)
+            .sum();
+        let remaining = max
+            .saturating_sub(state.total_ckbtc_in_pool)
+            .saturating_sub(pending_stakes);
+        if amount > remaining {
+            return Err(StakeError::ExceedsPoolCap { remaining });
+        }
+    }
+    if let Some(max) = state.staking_caps.max_stake_per_staker {
+        let remaining = max.saturating_sub(staker.otbtc_balance);
+        if amount > remaining {
+            return Err(StakeError::ExceedsStakerCap { remaining });
+        }
+    }
+    Ok(())
+}
+
+/// How a call is authorized to act for a staker.
+enum Authorization<'a> {
+    /// The call carries a signature by the owner of the address identifying the staker.
+    Signature(&'a Vec<u8>),
+    /// The staker is the principal account of the caller, authenticated by the IC.
+    Caller,
+}
+
+/// Check that a call is authorized to act for a staker.
+///
+/// A signature for an Ethereum address which is not recovered to the address is checked by
+/// the smart contract wallet at the address (EIP-1271), if configured and if the staker is
+/// registered as a smart contract wallet. As the check costs cycles, it is skipped when the
+/// cycles balance is low, and done at most once per `EIP1271_CHECK_INTERVAL` for each caller and
+/// staker.
+/// As this is asynchronous, `nonce_is_current` must be checked again before the nonce is
+/// consumed.
+async fn authorize(
+    staker: &Staker,
+    action: &str,
+    amount: u64,
+    destination: Option<&str>,
+    authorization: &Authorization<'_>,
+) -> Result<(), VerifySignatureError> {
+    let signature = match authorization {
+        Authorization::Signature(signature) => signature,
+        Authorization::Caller => return Ok(()),
     };
-    let ckbtc_ledger_account = state::read_state(|state| state.ckbtc_ledger_account);
-    ic_ledger_types::transfer(ckbtc_ledger_account, transfer_args)
-        .await
-        .map_err(|e| StakeError::CkbtcLedgerError(format!("failed to call ckBTC ledger: {:?}", e)))?
-        .map_err(|e| {
-            StakeError::CkbtcTransferError(format!("ckBTC ledger transfer error {:?}", e))
-        })?;
-    // Mint otBTC tokens on the ledger.
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: Tokens::from_e8s(args.amount),
-        fee: Tokens::from_e8s(10_000),
-        // The subaccount of the account identifier that will be used to withdraw tokens and send them
-        // to another account identifier. If set to None then the default subaccount will be used.
-        // See the [Ledger doc](https://internetcomputer.org/docs/current/developer-docs/integrations/ledger/#accounts).
-        from_subaccount: Some(subaccount.clone()),
-        to: AccountIdentifier::new(&ic_cdk::id(), &subaccount.clone()),
-        created_at_time: None,
+    let error = match verify_signature(staker, action, amount, destination, signature) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
     };
-    let otbtc_ledger_account = state::read_state(|state| state.otbtc_ledger_account);
-    ic_ledger_types::transfer(otbtc_ledger_account, transfer_args)
-        .await
-        .map_err(|e| StakeError::OtbtcLedgerError(format!("failed to call otBTC ledger: {:?}", e)))?
-        .map_err(|e| {
-            StakeError::OtbtcTransferError(format!("otBTC ledger transfer error {:?}", e))
-        })?;
-    // Change the state of the staking pool.
-    state::mutate_state(|state| {
-        staker.tx_nonce += 1;
-        staker.ckbtc_balance -= args.amount;
-        staker.otbtc_balance += args.amount;
-        state.stakers_map.insert(args.eth_address.clone(), staker);
-        state.total_ckbtc_in_pool += args.amount;
+    let eip1271_config = state::read_state(|state| state.eip1271_config.clone());
+    let config = match eip1271_config {
+        Some(config) if staker.is_contract_wallet => config,
+        _ => return Err(error),
+    };
+    if cycles::check_cycles_balance() {
+        return Err(VerifySignatureError::ContractCallFailed(
+            "the cycles balance is low".to_string(),
+        ));
+    }
+    let now = time();
+    // The checks are limited for each caller, so that a failed check by a stranger does not
+    // delay the checks by the owner of the wallet.
+    let key = (ic_cdk::caller(), staker.eth_address.clone());
+    let checked_recently = state::mutate_state(|state| {
+        state
+            .eip1271_last_checks
+            .retain(|_, last_check| now < last_check.saturating_add(EIP1271_CHECK_INTERVAL));
+        if state.eip1271_last_checks.contains_key(&key) {
+            return true;
+        }
+        state.eip1271_last_checks.insert(key, now);
+        false
     });
-    //
-    Ok(())
+    if checked_recently {
+        return Err(VerifySignatureError::ContractCallFailed(
+            "the contract was asked recently, retry later".to_string(),
+        ));
+    }
+    let message_hash = keccak256(signing_string(staker, action, amount, destination).as_bytes());
+    let result =
+        eip1271::verify_signature(&config, &staker.eth_address, message_hash, signature).await;
+    if let Err(VerifySignatureError::ContractCallFailed(e)) = &result {
+        log::warn(&format!("{} of {}", action, staker.eth_address), e.clone());
+    }
+    result
+}
+
+/// Whether the nonce of a staker is still the one signed for a call, which may have been
+/// consumed by another call while the signature was verified.
+fn nonce_is_current(
+    state: &BtcStakingPoolState,
+    staker: &Staker,
+    authorization: &Authorization,
+) -> bool {
+    match authorization {
+        Authorization::Signature(_) => state
+            .stakers_map
+            .get(&staker.eth_address)
+            .is_some_and(|current| current.tx_nonce == staker.tx_nonce),
+        Authorization::Caller => true,
+    }
+}
+
+/// The message `<nonce>:<action>:<amount>` signed for a call, followed by `:<destination>` for
+/// the actions sending tokens to a destination chosen by the staker.
+fn signing_string(staker: &Staker, action: &str, amount: u64, destination: Option<&str>) -> String {
+    let message = format!("{}:{}:{}", staker.tx_nonce, action, amount);
+    match destination {
+        Some(destination) => format!("{}:{}", message, destination),
+        None => message,
+    }
+}
+
+/// The staker the caller acts for, or `None` for the anonymous principal.
+///
+/// This is the Ethereum address of the active Sign-In with Ethereum session of the caller, if
+/// any, and otherwise the principal account of the caller, keyed by the textual representation
+/// of the principal.
+fn caller_account() -> Option<String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return None;
+    }
+    let now = time();
+    let session_address = state::read_state(|state| {
+        state
+            .siwe_sessions
+            .get(&caller)
+            .filter(|session| now < session.expires_at)
+            .map(|session| session.eth_address.clone())
+    });
+    Some(session_address.unwrap_or_else(|| caller.to_text()))
 }
+
+/// Verify the signature of the message of a call, see `signing_string`, by the owner of the
+/// address identifying a staker.
+///
+/// A principal account cannot be acted for with a signature.
 fn verify_signature(
     staker: &Staker,
     action: &str,
     amount: u64,
+    destination: Option<&str>,
     signature: &Vec<u8>,
+) -> Result<(), VerifySignatureError> {
+    if Principal::from_text(&staker.eth_address).is_ok() {
+        return Err(VerifySignatureError::SignerAddressMismatch);
+    }
+    let signing_string = signing_string(staker, action, amount, destination);
+    match parse_bitcoin_address(&staker.eth_address) {
+        Some(address) => {
+            btc_signature::verify_signature(&address, signing_string.as_bytes(), signature)
+        }
+        None => verify_eth_signature(&staker.eth_address, signing_string, signature),
+    }
+}
+
+fn verify_eth_signature(
+    eth_address: &str,
+    signing_string: String,
+    signature: &[u8],
 ) -> Result<(), VerifySignatureError> {
     if signature.len() != 65 {
         return Err(VerifySignatureError::InvalidSignatureLength);
     }
-    let signing_string = format!("{}:{}:{}", staker.tx_nonce, action, amount);
     let message = Message::parse_slice(&keccak256(&signing_string.into_bytes()))
         .map_err(|e| VerifySignatureError::FailedParsingSigningMessage(format!("{:?}", e)))?;
     let recid = RecoveryId::parse(signature[64])
         .map_err(|e| VerifySignatureError::InvalidRecoveryIdInSignature(format!("{:?}", e)))?;
-    let recoverable_signature = Signature::parse_standard_slice(&signature[..63])
+    let recoverable_signature = Signature::parse_standard_slice(&signature[..64])
         .map_err(|e| VerifySignatureError::FailedParsingSignature(format!("{:?}", e)))?;
     let pubkey = libsecp256k1::recover(&message, &recoverable_signature, &recid)
         .map_err(|e| VerifySignatureError::FailedRecoveringPublicKey(format!("{:?}", e)))?;
     let pubkey_bytes = pubkey.serialize();
     let hash = keccak256(&pubkey_bytes[1..]);
     let address = &hash[12..];
-    if hex::encode(address) == staker.eth_address {
+    if hex::encode(address) == eth_address {
         Ok(())
     } else {
         Err(VerifySignatureError::SignerAddressMismatch)
@@ -222,164 +916,1845 @@ fn verify_signature(
 }
 
 #[update]
-async fn unstake(args: UnstakeArgs) -> Result<(), UnstakeError> {
-    let subaccount = convert_eth_address_to_subaccount(&args.eth_address)
+async fn unstake(args: UnstakeArgs) -> Result<UnstakeResponse, UnstakeError> {
+    unstake_for(
+        args.eth_address,
+        args.amount,
+        Authorization::Signature(&args.signature),
+    )
+    .await
+}
+
+/// Unstake for the principal account of the caller.
+#[update]
+async fn unstake_for_caller(amount: u64) -> Result<UnstakeResponse, UnstakeError> {
+    let eth_address = caller_account().ok_or(UnstakeError::AnonymousCaller)?;
+    unstake_for(eth_address, amount, Authorization::Caller).await
+}
+
+async fn unstake_for(
+    eth_address: String,
+    amount: u64,
+    authorization: Authorization<'_>,
+) -> Result<UnstakeResponse, UnstakeError> {
+    let eth_address =
+        canonical_staker_address(&eth_address).ok_or(UnstakeError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
         .map_err(|_| UnstakeError::InvalidEthereumAddress)?;
-    let mut staker = state::take_state(|state| {
+    let min = state::read_state(|state| effective_minimum_amounts(state).unstake);
+    if amount < min {
+        return Err(UnstakeError::AmountTooLow { min });
+    }
+    let staker = state::read_state(|state| {
         state
             .stakers_map
-            .get(&args.eth_address)
+            .get(&eth_address)
             .ok_or(UnstakeError::LackOfStakerRecord)
             .cloned()
     })?;
-    if staker.otbtc_balance < args.amount {
+    if staker.otbtc_balance < amount {
         return Err(UnstakeError::NotEnoughOtbtcBalance);
     }
-    // Verify the signature.
-    if verify_signature(&staker, "unstake", args.amount, &args.signature).is_err() {
+    if authorize(&staker, "unstake", amount, None, &authorization)
+        .await
+        .is_err()
+    {
         return Err(UnstakeError::InvalidSignature);
     }
-    // Burn otBTC tokens on the ledger.
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: Tokens::from_e8s(args.amount),
-        fee: Tokens::from_e8s(10_000),
-        // The subaccount of the account identifier that will be used to withdraw tokens and send them
-        // to another account identifier. If set to None then the default subaccount will be used.
-        // See the [Ledger doc](https://internetcomputer.org/docs/current/developer-docs/integrations/ledger/#accounts).
-        from_subaccount: Some(subaccount.clone()),
-        to: AccountIdentifier::new(&ic_cdk::id(), &Subaccount([0u8; 32])),
-        created_at_time: None,
-    };
-    let otbtc_ledger_account = state::read_state(|state| state.otbtc_ledger_account);
-    ic_ledger_types::transfer(otbtc_ledger_account, transfer_args)
-        .await
-        .map_err(|e| {
-            UnstakeError::OtbtcLedgerError(format!("failed to call otBTC ledger: {:?}", e))
-        })?
-        .map_err(|e| {
-            UnstakeError::OtbtcTransferError(format!("otBTC ledger transfer error {:?}", e))
-        })?;
-    // Change the state
-    state::mutate_state(|state| {
-        staker.tx_nonce += 1;
-        staker.otbtc_balance -= args.amount;
-        state.stakers_map.insert(args.eth_address.clone(), staker);
-        state.unstaking_queue.push_back(UnstakeRequest {
-            eth_address: args.eth_address.clone(),
-            amount: args.amount,
-            unlock_time: ic_cdk::api::time() + state.unbonding_period,
-        });
-    });
-    //
-    Ok(())
+    // Record the operation, which burns otBTC tokens by transferring them to the main account
+    // (the minting account) and then queues the unstake request.
+    let operation_id = state::mutate_state(|state| {
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(UnstakeError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(UnstakeError::InvalidSignature);
+        }
+        let nonce = consume_nonce(state, &eth_address);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::Unstake,
+                eth_address: &eth_address,
+                subaccount,
+                amount,
+                fee: 0,
+                protocol_fee: 0,
+                destination: None,
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(UnstakeResponse {
+            operation_id,
+            amount: op.amount,
+            fee: op.fee,
+        }),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(UnstakeError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
 }
 
+/// Unlock the first request in the unstaking queue if its unlock time is reached.
+///
+/// The ckBTC ledger fee of moving the unlocked ckBTC back to the subaccount of the staker
+/// is paid by the staker, out of the unlocked amount.
 #[update]
-async fn unlock_tokens_in_queue() -> Result<(), UnlockTokensInQueueError> {
-    let unstake_request =
-        state::read_state(|state| state.unstaking_queue.front().map(|v| v.clone()));
-    if let Some(request) = unstake_request {
-        let staker = state::read_state(|state| {
-            state
-                .stakers_map
-                .get(&request.eth_address)
-                .ok_or(UnlockTokensInQueueError::LackOfStakerRecord)
-                .cloned()
-        })?;
-        if ic_cdk::api::time() < request.unlock_time {
+async fn unlock_tokens_in_queue() -> Result<Option<UnlockTokensResponse>, UnlockTokensInQueueError>
+{
+    let now = time();
+    let operation_id = state::mutate_state(|state| {
+        let Some(request) = state.unstaking_queue.front().cloned() else {
+            return Ok(None);
+        };
+        let staker = state
+            .stakers_map
+            .get(&request.eth_address)
+            .ok_or(UnlockTokensInQueueError::LackOfStakerRecord)?;
+        if now < request.unlock_time {
             return Err(UnlockTokensInQueueError::UnlockTimeNotReached);
         }
-        // Transfer ckBTC tokens to the subaccount.
-        let transfer_args = ic_ledger_types::TransferArgs {
-            memo: Memo(0),
-            amount: Tokens::from_e8s(request.amount),
-            fee: Tokens::from_e8s(10_000),
-            // The subaccount of the account identifier that will be used to withdraw tokens and send them
-            // to another account identifier. If set to None then the default subaccount will be used.
-            // See the [Ledger doc](https://internetcomputer.org/docs/current/developer-docs/integrations/ledger/#accounts).
-            from_subaccount: None,
-            to: AccountIdentifier::new(&ic_cdk::id(), &staker.subaccount.clone()),
-            created_at_time: None,
-        };
-        let ckbtc_ledger_account = state::read_state(|state| state.ckbtc_ledger_account);
-        ic_ledger_types::transfer(ckbtc_ledger_account, transfer_args)
-            .await
-            .map_err(|e| {
-                UnlockTokensInQueueError::CkbtcLedgerError(format!(
-                    "failed to call ckBTC ledger: {:?}",
-                    e
-                ))
-            })?
-            .map_err(|e| {
-                UnlockTokensInQueueError::CkbtcTransferError(format!(
-                    "ckBTC ledger transfer error {:?}",
-                    e
-                ))
-            })?;
-        // Change the state
-        state::mutate_state(|state| {
-            state.unstaking_queue.pop_front();
-            state.total_ckbtc_in_pool -= request.amount;
-            let staker = state
-                .stakers_map
-                .get_mut(&request.eth_address)
-                .expect("staker not found, should not happen");
-            staker.ckbtc_balance += request.amount;
-        });
+        if state.staker_locks.contains_key(&request.eth_address) {
+            return Err(UnlockTokensInQueueError::StakerBusy);
+        }
+        let subaccount = staker.subaccount;
+        // An amount which does not cover the fee is left in the main account.
+        let fee = state.ckbtc_transfer_fee.min(request.amount);
+        state.unstaking_queue.pop_front();
+        Ok(Some(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::UnlockTokens,
+                eth_address: &request.eth_address,
+                subaccount,
+                amount: request.amount,
+                fee,
+                protocol_fee: 0,
+                destination: None,
+                nonce: None,
+            },
+        )))
+    })?;
+    let Some(operation_id) = operation_id else {
+        return Ok(None);
+    };
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(Some(UnlockTokensResponse {
+            operation_id,
+            eth_address: op.eth_address,
+            amount: op.amount - op.fee,
+            fee: op.fee,
+        })),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(UnlockTokensInQueueError::OperationPending {
+            operation_id,
+            reason,
+        }),
     }
-    //
-    Ok(())
 }
 
 #[update]
-async fn withdraw_btc(args: WithdrawBtcArgs) -> Result<(), WithdrawBtcError> {
-    let subaccount = convert_eth_address_to_subaccount(&args.eth_address)
+async fn withdraw_btc(args: WithdrawBtcArgs) -> Result<WithdrawBtcResponse, WithdrawBtcError> {
+    withdraw_btc_for(
+        args.eth_address,
+        args.amount,
+        Authorization::Signature(&args.signature),
+    )
+    .await
+}
+
+/// Withdraw BTC for the principal account of the caller.
+#[update]
+async fn withdraw_btc_for_caller(amount: u64) -> Result<WithdrawBtcResponse, WithdrawBtcError> {
+    let eth_address = caller_account().ok_or(WithdrawBtcError::AnonymousCaller)?;
+    withdraw_btc_for(eth_address, amount, Authorization::Caller).await
+}
+
+async fn withdraw_btc_for(
+    eth_address: String,
+    amount: u64,
+    authorization: Authorization<'_>,
+) -> Result<WithdrawBtcResponse, WithdrawBtcError> {
+    let eth_address =
+        canonical_staker_address(&eth_address).ok_or(WithdrawBtcError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
         .map_err(|_| WithdrawBtcError::InvalidEthereumAddress)?;
-    let mut staker = state::take_state(|state| {
+    let min = state::read_state(|state| effective_minimum_amounts(state).withdraw_btc);
+    if amount < min {
+        return Err(WithdrawBtcError::AmountTooLow { min });
+    }
+    let staker = state::read_state(|state| {
         state
             .stakers_map
-            .get(&args.eth_address)
+            .get(&eth_address)
             .ok_or(WithdrawBtcError::LackOfStakerRecord)
             .cloned()
     })?;
-    if staker.ckbtc_balance < args.amount {
+    // The protocol fee is moved to the treasury, and the staker pays the fee of that transfer.
+    let (protocol_fee, fee) = state::read_state(|state| {
+        let protocol_fee = apply_bps(amount, state.protocol_fee_config.withdrawal_fee_bps);
+        if protocol_fee > 0 {
+            (protocol_fee, state.ckbtc_transfer_fee)
+        } else {
+            (0, 0)
+        }
+    });
+    if staker.ckbtc_balance < amount.saturating_add(fee) {
         return Err(WithdrawBtcError::NotEnoughCkbtcBalance);
     }
-    // Verify the signature.
-    if verify_signature(&staker, "withdraw_btc", args.amount, &args.signature).is_err() {
+    if authorize(&staker, "withdraw_btc", amount, None, &authorization)
+        .await
+        .is_err()
+    {
         return Err(WithdrawBtcError::InvalidSignature);
     }
-    // Burn ckBTC tokens from the subaccount.
-    let ckbtc_minting_account = state::read_state(|state| state.ckbtc_minting_account);
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: Tokens::from_e8s(args.amount),
-        fee: Tokens::from_e8s(10_000),
-        // The subaccount of the account identifier that will be used to withdraw tokens and send them
-        // to another account identifier. If set to None then the default subaccount will be used.
-        // See the [Ledger doc](https://internetcomputer.org/docs/current/developer-docs/integrations/ledger/#accounts).
-        from_subaccount: Some(subaccount.clone()),
-        to: AccountIdentifier::new(&ckbtc_minting_account, &Subaccount([0u8; 32])),
-        created_at_time: None,
-    };
-    let ckbtc_ledger_account = state::read_state(|state| state.ckbtc_ledger_account);
-    ic_ledger_types::transfer(ckbtc_ledger_account, transfer_args)
-        .await
-        .map_err(|e| {
-            WithdrawBtcError::CkbtcLedgerError(format!("failed to call ckBTC ledger: {:?}", e))
-        })?
-        .map_err(|e| {
-            WithdrawBtcError::CkbtcTransferError(format!("ckBTC ledger transfer error {:?}", e))
-        })?;
-    // Change the state
-    state::mutate_state(|state| {
-        staker.tx_nonce += 1;
-        staker.ckbtc_balance -= args.amount;
-        state.stakers_map.insert(args.eth_address.clone(), staker);
-    });
-    //
-    Ok(())
+    // Record the operation, which burns ckBTC tokens by transferring them to the ckBTC minter
+    // (the minting account) and then transfers the protocol fee to the treasury subaccount.
+    let operation_id = state::mutate_state(|state| {
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(WithdrawBtcError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(WithdrawBtcError::InvalidSignature);
+        }
+        let nonce = consume_nonce(state, &eth_address);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::WithdrawBtc,
+                eth_address: &eth_address,
+                subaccount,
+                amount,
+                fee,
+                protocol_fee,
+                destination: None,
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(WithdrawBtcResponse {
+            operation_id,
+            amount: op.amount - op.protocol_fee,
+            fee: op.fee,
+            protocol_fee: op.protocol_fee,
+        }),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(WithdrawBtcError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
 }
 
-ic_cdk::export_candid!();
+/// Transfer otBTC from the subaccount of a staker to the subaccount of another staker, which
+/// is created if it does not exist.
+///
+/// The recipient is bound into the signed message `<nonce>:transfer_otbtc:<amount>:<to>`.
+#[update]
+async fn transfer_otbtc(
+    args: TransferOtbtcArgs,
+) -> Result<TransferOtbtcResponse, TransferOtbtcError> {
+    transfer_otbtc_for(
+        args.from,
+        args.to,
+        args.amount,
+        Authorization::Signature(&args.signature),
+    )
+    .await
+}
+
+/// Transfer otBTC from the principal account of the caller to another staker.
+#[update]
+async fn transfer_otbtc_for_caller(
+    to: String,
+    amount: u64,
+) -> Result<TransferOtbtcResponse, TransferOtbtcError> {
+    let from = caller_account().ok_or(TransferOtbtcError::AnonymousCaller)?;
+    transfer_otbtc_for(from, to, amount, Authorization::Caller).await
+}
+
+async fn transfer_otbtc_for(
+    from: String,
+    to: String,
+    amount: u64,
+    authorization: Authorization<'_>,
+) -> Result<TransferOtbtcResponse, TransferOtbtcError> {
+    let from = canonical_staker_address(&from).ok_or(TransferOtbtcError::InvalidEthereumAddress)?;
+    let to = canonical_staker_address(&to).ok_or(TransferOtbtcError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&from)
+        .map_err(|_| TransferOtbtcError::InvalidEthereumAddress)?;
+    let recipient_subaccount = convert_staker_address_to_subaccount(&to)
+        .map_err(|_| TransferOtbtcError::InvalidRecipient)?;
+    if to == from {
+        return Err(TransferOtbtcError::InvalidRecipient);
+    }
+    let staker = state::read_state(|state| {
+        state
+            .stakers_map
+            .get(&from)
+            .ok_or(TransferOtbtcError::LackOfStakerRecord)
+            .cloned()
+    })?;
+    // The sender pays the fee of the transfer.
+    let fee = state::read_state(|state| state.otbtc_transfer_fee);
+    let min = state::read_state(|state| effective_minimum_amounts(state).transfer_otbtc);
+    if amount < min {
+        return Err(TransferOtbtcError::AmountTooLow { min });
+    }
+    if staker.otbtc_balance < amount.saturating_add(fee) {
+        return Err(TransferOtbtcError::NotEnoughOtbtcBalance);
+    }
+    if authorize(&staker, "transfer_otbtc", amount, Some(&to), &authorization)
+        .await
+        .is_err()
+    {
+        return Err(TransferOtbtcError::InvalidSignature);
+    }
+    // Record the operation, which transfers otBTC tokens between the subaccounts and then moves
+    // the amount between the balances of the stakers.
+    let operation_id = state::mutate_state(|state| {
+        // The recipient is locked by the operation too, see `journal::create_operation`.
+        if state.staker_locks.contains_key(&from) || state.staker_locks.contains_key(&to) {
+            return Err(TransferOtbtcError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(TransferOtbtcError::InvalidSignature);
+        }
+        let nonce = consume_nonce(state, &from);
+        state
+            .stakers_map
+            .entry(to.clone())
+            .or_insert_with(|| Staker::new(to.clone(), recipient_subaccount));
+        certification::certify_staker(state, &to);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::TransferOtbtc,
+                eth_address: &from,
+                subaccount,
+                amount,
+                fee,
+                protocol_fee: 0,
+                destination: Some(Destination::Staker(to.clone())),
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(TransferOtbtcResponse {
+            operation_id,
+            amount: op.amount,
+            fee: op.fee,
+        }),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(TransferOtbtcError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
+}
+
+/// Export otBTC from the subaccount of a staker to an account outside the pool.
+///
+/// The destination is bound into the signed message `<nonce>:export_otbtc:<amount>:<to>`.
+#[update]
+async fn export_otbtc(args: ExportOtbtcArgs) -> Result<ExportOtbtcResponse, ExportOtbtcError> {
+    export_otbtc_for(
+        args.eth_address,
+        args.amount,
+        args.to,
+        Authorization::Signature(&args.signature),
+    )
+    .await
+}
+
+/// Export otBTC from the principal account of the caller to an account outside the pool.
+#[update]
+async fn export_otbtc_for_caller(
+    amount: u64,
+    to: Account,
+) -> Result<ExportOtbtcResponse, ExportOtbtcError> {
+    let eth_address = caller_account().ok_or(ExportOtbtcError::AnonymousCaller)?;
+    export_otbtc_for(eth_address, amount, to, Authorization::Caller).await
+}
+
+async fn export_otbtc_for(
+    eth_address: String,
+    amount: u64,
+    to: Account,
+    authorization: Authorization<'_>,
+) -> Result<ExportOtbtcResponse, ExportOtbtcError> {
+    let eth_address =
+        canonical_staker_address(&eth_address).ok_or(ExportOtbtcError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
+        .map_err(|_| ExportOtbtcError::InvalidEthereumAddress)?;
+    let staker = state::read_state(|state| {
+        state
+            .stakers_map
+            .get(&eth_address)
+            .ok_or(ExportOtbtcError::LackOfStakerRecord)
+            .cloned()
+    })?;
+    // The staker pays the fee of the transfer.
+    let fee = state::read_state(|state| state.otbtc_transfer_fee);
+    let min = state::read_state(|state| effective_minimum_amounts(state).export_otbtc);
+    if amount < min {
+        return Err(ExportOtbtcError::AmountTooLow { min });
+    }
+    if staker.otbtc_balance < amount.saturating_add(fee) {
+        return Err(ExportOtbtcError::NotEnoughOtbtcBalance);
+    }
+    let destination = to.to_string();
+    if authorize(
+        &staker,
+        "export_otbtc",
+        amount,
+        Some(&destination),
+        &authorization,
+    )
+    .await
+    .is_err()
+    {
+        return Err(ExportOtbtcError::InvalidSignature);
+    }
+    // Record the operation, which transfers otBTC tokens out of the subaccount of the staker.
+    let operation_id = state::mutate_state(|state| {
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(ExportOtbtcError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(ExportOtbtcError::InvalidSignature);
+        }
+        let nonce = consume_nonce(state, &eth_address);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::ExportOtbtc,
+                eth_address: &eth_address,
+                subaccount,
+                amount,
+                fee,
+                protocol_fee: 0,
+                destination: Some(Destination::Account(to)),
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(ExportOtbtcResponse {
+            operation_id,
+            amount: op.amount,
+            fee: op.fee,
+        }),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(ExportOtbtcError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
+}
+
+/// Import otBTC approved by the caller (ICRC-2) into the subaccount of a staker, which is
+/// created if it does not exist.
+///
+/// Returns the credited amount. As with `update_balance`, the staker is credited with the otBTC
+/// in its subaccount which is not accounted for yet, which includes the imported amount even if
+/// its transfer is not confirmed.
+#[update]
+async fn import_otbtc(args: ImportOtbtcArgs) -> Result<u64, ImportOtbtcError> {
+    let args = ImportOtbtcArgs {
+        eth_address: canonical_staker_address(&args.eth_address)
+            .ok_or(ImportOtbtcError::InvalidEthereumAddress)?,
+        ..args
+    };
+    let subaccount = convert_staker_address_to_subaccount(&args.eth_address)
+        .map_err(|_| ImportOtbtcError::InvalidEthereumAddress)?;
+    let _guard =
+        journal::lock_for_balance_sync(&args.eth_address).ok_or(ImportOtbtcError::StakerBusy)?;
+    let (otbtc_ledger_account, nonce) = state::mutate_state(|state| {
+        (
+            state.otbtc_ledger_account,
+            journal::next_operation_id(state),
+        )
+    });
+    let pool = ic_cdk::id();
+    let transfer_result = ledger::transfer_from(
+        otbtc_ledger_account,
+        TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: ic_cdk::caller(),
+                subaccount: args.from_subaccount,
+            },
+            to: Account {
+                owner: pool,
+                subaccount: Some(subaccount),
+            },
+            amount: args.amount.into(),
+            fee: None,
+            memo: ledger::memo_bytes(ledger::memo(MemoAction::ImportOtbtc, nonce)),
+            created_at_time: Some(time()),
+        },
+    )
+    .await;
+    let log_context = format!("import_otbtc of {}", args.eth_address);
+    let transfer_error = match transfer_result {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(ImportOtbtcError::OtbtcTransferError(format!(
+            "otBTC ledger transfer error {:?}",
+            e
+        ))),
+        Err(e) => Some(ImportOtbtcError::OtbtcLedgerError(format!(
+            "failed to call otBTC ledger: {:?}",
+            e
+        ))),
+    };
+    let balance = ledger::balance_of(
+        otbtc_ledger_account,
+        Account {
+            owner: pool,
+            subaccount: Some(subaccount),
+        },
+    )
+    .await
+    .map_err(|e| {
+        ImportOtbtcError::OtbtcLedgerError(format!("failed to call otBTC ledger: {:?}", e))
+    })?;
+    let credited = state::mutate_state(|state| {
+        let staker = state
+            .stakers_map
+            .entry(args.eth_address.clone())
+            .or_insert_with(|| Staker::new(args.eth_address.clone(), subaccount));
+        let credited = balance.saturating_sub(staker.otbtc_balance);
+        staker.otbtc_balance += credited;
+        certification::certify_staker(state, &args.eth_address);
+        credited
+    });
+    if credited > 0 {
+        log::info(&log_context, format!("credited {} otBTC", credited));
+    }
+    match transfer_error {
+        Some(e) if credited == 0 => {
+            log::warn(&log_context, format!("{:?}", e));
+            Err(e)
+        }
+        _ => Ok(credited),
+    }
+}
+
+/// Deposit ckBTC approved by the caller (ICRC-2) into the subaccount of a staker, which is
+/// created if it does not exist.
+///
+/// Returns the credited amount, which is computed like in `update_balance`.
+#[update]
+async fn deposit_ckbtc(args: DepositCkbtcArgs) -> Result<u64, DepositCkbtcError> {
+    let args = DepositCkbtcArgs {
+        eth_address: canonical_staker_address(&args.eth_address)
+            .ok_or(DepositCkbtcError::InvalidEthereumAddress)?,
+        ..args
+    };
+    let subaccount = convert_staker_address_to_subaccount(&args.eth_address)
+        .map_err(|_| DepositCkbtcError::InvalidEthereumAddress)?;
+    let _guard =
+        journal::lock_for_balance_sync(&args.eth_address).ok_or(DepositCkbtcError::StakerBusy)?;
+    let (ckbtc_ledger_account, nonce) = state::mutate_state(|state| {
+        (
+            state.ckbtc_ledger_account,
+            journal::next_operation_id(state),
+        )
+    });
+    let transfer_result = ledger::transfer_from(
+        ckbtc_ledger_account,
+        TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: ic_cdk::caller(),
+                subaccount: args.from_subaccount,
+            },
+            to: Account {
+                owner: ic_cdk::id(),
+                subaccount: Some(subaccount),
+            },
+            amount: args.amount.into(),
+            fee: None,
+            memo: ledger::memo_bytes(ledger::memo(MemoAction::DepositCkbtc, nonce)),
+            created_at_time: Some(time()),
+        },
+    )
+    .await;
+    let log_context = format!("deposit_ckbtc of {}", args.eth_address);
+    let transfer_error = match transfer_result {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(DepositCkbtcError::CkbtcTransferError(format!(
+            "ckBTC ledger transfer error {:?}",
+            e
+        ))),
+        Err(e) => Some(DepositCkbtcError::CkbtcLedgerError(format!(
+            "failed to call ckBTC ledger: {:?}",
+            e
+        ))),
+    };
+    let credited = credit_unaccounted_ckbtc(&args.eth_address, subaccount)
+        .await
+        .map_err(DepositCkbtcError::CkbtcLedgerError)?;
+    if credited > 0 {
+        log::info(&log_context, format!("credited {} ckBTC", credited));
+    }
+    match transfer_error {
+        Some(e) if credited == 0 => {
+            log::warn(&log_context, format!("{:?}", e));
+            Err(e)
+        }
+        _ => Ok(credited),
+    }
+}
+
+/// Withdraw ckBTC from the subaccount of a staker to an account outside the pool.
+///
+/// The destination is bound into the signed message `<nonce>:withdraw_ckbtc:<amount>:<to>`.
+#[update]
+async fn withdraw_ckbtc(
+    args: WithdrawCkbtcArgs,
+) -> Result<WithdrawCkbtcResponse, WithdrawCkbtcError> {
+    withdraw_ckbtc_for(
+        args.eth_address,
+        args.amount,
+        args.to,
+        Authorization::Signature(&args.signature),
+    )
+    .await
+}
+
+/// Withdraw ckBTC from the principal account of the caller to an account outside the pool.
+#[update]
+async fn withdraw_ckbtc_for_caller(
+    amount: u64,
+    to: Account,
+) -> Result<WithdrawCkbtcResponse, WithdrawCkbtcError> {
+    let eth_address = caller_account().ok_or(WithdrawCkbtcError::AnonymousCaller)?;
+    withdraw_ckbtc_for(eth_address, amount, to, Authorization::Caller).await
+}
+
+async fn withdraw_ckbtc_for(
+    eth_address: String,
+    amount: u64,
+    to: Account,
+    authorization: Authorization<'_>,
+) -> Result<WithdrawCkbtcResponse, WithdrawCkbtcError> {
+    let eth_address =
+        canonical_staker_address(&eth_address).ok_or(WithdrawCkbtcError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
+        .map_err(|_| WithdrawCkbtcError::InvalidEthereumAddress)?;
+    let staker = state::read_state(|state| {
+        state
+            .stakers_map
+            .get(&eth_address)
+            .ok_or(WithdrawCkbtcError::LackOfStakerRecord)
+            .cloned()
+    })?;
+    // The protocol fee of withdrawals is moved to the treasury, and the staker pays the fees of
+    // both transfers.
+    let (protocol_fee, fee) = state::read_state(|state| {
+        (
+            apply_bps(amount, state.protocol_fee_config.withdrawal_fee_bps),
+            state.ckbtc_transfer_fee,
+        )
+    });
+    let min = state::read_state(|state| effective_minimum_amounts(state).withdraw_ckbtc);
+    if amount < min {
+        return Err(WithdrawCkbtcError::AmountTooLow { min });
+    }
+    let fees = if protocol_fee > 0 { 2 * fee } else { fee };
+    if staker.ckbtc_balance < amount.saturating_add(fees) {
+        return Err(WithdrawCkbtcError::NotEnoughCkbtcBalance);
+    }
+    let destination = to.to_string();
+    if authorize(
+        &staker,
+        "withdraw_ckbtc",
+        amount,
+        Some(&destination),
+        &authorization,
+    )
+    .await
+    .is_err()
+    {
+        return Err(WithdrawCkbtcError::InvalidSignature);
+    }
+    // Record the operation, which transfers ckBTC tokens out of the subaccount of the staker and
+    // then transfers the protocol fee to the treasury subaccount.
+    let operation_id = state::mutate_state(|state| {
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(WithdrawCkbtcError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(WithdrawCkbtcError::InvalidSignature);
+        }
+        let nonce = consume_nonce(state, &eth_address);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::WithdrawCkbtc,
+                eth_address: &eth_address,
+                subaccount,
+                amount,
+                fee,
+                protocol_fee,
+                destination: Some(Destination::Account(to)),
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(WithdrawCkbtcResponse {
+            operation_id,
+            amount: op.amount - op.protocol_fee,
+            fee: if op.protocol_fee > 0 {
+                2 * op.fee
+            } else {
+                op.fee
+            },
+            protocol_fee: op.protocol_fee,
+        }),
+        Outcome::Failed(error) => Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(WithdrawCkbtcError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
+}
+
+/// The subaccount of the pool which holds the protocol fees.
+///
+/// The hashed input is not 20 bytes long, so it cannot collide with the subaccount of
+/// an Ethereum address.
+fn treasury_subaccount() -> Subaccount {
+    Subaccount(keccak256(b"treasury"))
+}
+
+/// Compute the part of an amount given in basis points, rounded down.
+fn apply_bps(amount: u64, bps: u16) -> u64 {
+    (amount as u128 * bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+/// The minimum amounts which are actually enforced, raised by what the operations need to
+/// make sense: the unlocked amount must cover the ckBTC ledger fee, and the burned amount
+/// must reach the minimum withdrawal amount of the ckBTC minter.
+fn effective_minimum_amounts(state: &BtcStakingPoolState) -> MinimumAmounts {
+    let withdrawal_fee_bps = state.protocol_fee_config.withdrawal_fee_bps;
+    let min_withdrawal = if withdrawal_fee_bps >= BPS_DENOMINATOR {
+        u64::MAX
+    } else {
+        // The smallest amount whose part left after the protocol fee reaches the minimum.
+        let net_bps = (BPS_DENOMINATOR - withdrawal_fee_bps) as u128;
+        let min =
+            (state.retrieve_btc_min_amount as u128 * BPS_DENOMINATOR as u128).div_ceil(net_bps);
+        min.min(u64::MAX as u128) as u64
+    };
+    MinimumAmounts {
+        // The ckBTC transfer fee paid on top of a stake must not exceed the staked amount.
+        stake: state
+            .minimum_amounts
+            .stake
+            .max(state.ckbtc_transfer_fee + 1),
+        unstake: state
+            .minimum_amounts
+            .unstake
+            .max(state.ckbtc_transfer_fee + 1),
+        withdraw_btc: state
+            .minimum_amounts
+            .withdraw_btc
+            .max(min_withdrawal)
+            .max(1),
+        // The amounts sent by the other transfers must be larger than their fees.
+        transfer_otbtc: state
+            .minimum_amounts
+            .transfer_otbtc
+            .max(state.otbtc_transfer_fee + 1),
+        export_otbtc: state
+            .minimum_amounts
+            .export_otbtc
+            .max(state.otbtc_transfer_fee + 1),
+        withdraw_ckbtc: state
+            .minimum_amounts
+            .withdraw_ckbtc
+            .max(state.ckbtc_transfer_fee + 1),
+        bridge_otbtc: state
+            .minimum_amounts
+            .bridge_otbtc
+            .max(state.otbtc_transfer_fee + 1),
+    }
+}
+
+fn ensure_controller() -> Result<(), ConfigError> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(ConfigError::NotController)
+    }
+}
+
+/// Distribute the staking rewards in the main account to the stakers.
+///
+/// The ckBTC in the main account above `total_ckbtc_in_pool` is considered as rewards, so the
+/// rewards are not distributed while an operation moving ckBTC in or out of the main account is
+/// unfinished. The protocol fee is moved to the treasury, and the rest is staked on behalf of the
+/// stakers, in proportion to their otBTC balances, by minting otBTC for them. Each mint is
+/// journaled as an operation, which is executed in the background.
+#[update]
+async fn distribute_rewards() -> Result<DistributeRewardsResponse, DistributeRewardsError> {
+    ensure_controller().map_err(|_| DistributeRewardsError::NotController)?;
+    let ckbtc_ledger_account = state::read_state(|state| state.ckbtc_ledger_account);
+    let balance = ledger::balance_of(
+        ckbtc_ledger_account,
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+    )
+    .await
+    .map_err(|e| {
+        DistributeRewardsError::CkbtcLedgerError(format!("failed to call ckBTC ledger: {:?}", e))
+    })?;
+    // Reserve the rewards at once, so that a concurrent call cannot distribute them again.
+    let (rewards, protocol_fee, fee, nonce) = state::mutate_state(|state| {
+        let unsettled = state.operations.values().any(|op| {
+            matches!(
+                op.kind,
+                OperationKind::Stake | OperationKind::Unstake | OperationKind::UnlockTokens
+            ) && (op.status == OperationStatus::InProgress || op.needs_operator)
+        });
+        if unsettled {
+            return Err(DistributeRewardsError::OperationsInProgress);
+        }
+        let rewards = balance.saturating_sub(state.total_ckbtc_in_pool);
+        let protocol_fee = apply_bps(rewards, state.protocol_fee_config.reward_fee_bps);
+        let fee = if protocol_fee > 0 {
+            state.ckbtc_transfer_fee
+        } else {
+            0
+        };
+        if rewards <= protocol_fee + fee {
+            return Ok((0, 0, 0, 0));
+        }
+        state.total_ckbtc_in_pool += rewards;
+        certification::certify_pool(state);
+        Ok((
+            rewards,
+            protocol_fee,
+            fee,
+            journal::next_operation_id(state),
+        ))
+    })?;
+    if rewards == 0 {
+        return Ok(DistributeRewardsResponse::default());
+    }
+    if protocol_fee > 0 {
+        // Transfer the protocol fee to the treasury subaccount, the fee of the transfer is
+        // paid out of the rewards.
+        let transfer = PoolTransfer {
+            kind: PoolTransferKind::ProtocolFee,
+            // The main account of the pool.
+            from_subaccount: None,
+            to: Account {
+                owner: ic_cdk::id(),
+                subaccount: Some(treasury_subaccount()),
+            },
+            amount: protocol_fee,
+            fee,
+            memo: ledger::memo(MemoAction::DistributeRewards, nonce),
+            created_at: time(),
+        };
+        // The protocol fee is moved out of the reserved rewards when the transfer completes,
+        // which may be later if its outcome is unknown, and released if the ledger rejects it.
+        match journal::run_pool_transfer(nonce, transfer).await {
+            PoolTransferOutcome::Completed => {}
+            PoolTransferOutcome::Rejected(e) => {
+                // Release the rest of the reserved rewards.
+                state::mutate_state(|state| {
+                    state.total_ckbtc_in_pool -= rewards - protocol_fee - fee;
+                    certification::certify_pool(state);
+                });
+                return Err(DistributeRewardsError::CkbtcTransferError(e));
+            }
+            PoolTransferOutcome::Pending(reason) => log::warn(
+                "distribute_rewards",
+                format!("the transfer of the protocol fee is pending: {}", reason),
+            ),
+        }
+    }
+    let net_rewards = rewards - protocol_fee - fee;
+    // Journal a mint for each staker at once. The share of a staker locked by an unfinished
+    // operation and the rounding remainder are left for the next distribution.
+    let (operation_ids, distributed) = state::mutate_state(|state| {
+        let total_otbtc: u128 = state
+            .stakers_map
+            .values()
+            .map(|staker| staker.otbtc_balance as u128)
+            .sum();
+        let shares: Vec<(String, Subaccount, u64)> = if total_otbtc == 0 {
+            vec![]
+        } else {
+            state
+                .stakers_map
+                .values()
+                .filter(|staker| !state.staker_locks.contains_key(&staker.eth_address))
+                .map(|staker| {
+                    let share = net_rewards as u128 * staker.otbtc_balance as u128 / total_otbtc;
+                    (staker.eth_address.clone(), staker.subaccount, share as u64)
+                })
+                .filter(|(_, _, share)| *share > 0)
+                .collect()
+        };
+        let distributed: u64 = shares.iter().map(|(_, _, share)| share).sum();
+        state.total_ckbtc_in_pool -= net_rewards - distributed;
+        certification::certify_pool(state);
+        let operation_ids: Vec<u64> = shares
+            .into_iter()
+            .map(|(eth_address, subaccount, share)| {
+                journal::create_operation(
+                    state,
+                    OperationRequest {
+                        kind: OperationKind::DistributeRewards,
+                        eth_address: &eth_address,
+                        subaccount,
+                        amount: share,
+                        fee: 0,
+                        protocol_fee: 0,
+                        destination: None,
+                        nonce: None,
+                    },
+                )
+            })
+            .collect();
+        (operation_ids, distributed)
+    });
+    // Mint in the background, an interrupted mint is resumed by the recovery timer.
+    ic_cdk::spawn(async move {
+        for operation_id in operation_ids {
+            let _ = journal::run(operation_id).await;
+        }
+    });
+    log::info(
+        "distribute_rewards",
+        format!(
+            "distributing {} of {} ckBTC rewards, protocol fee {}",
+            distributed, rewards, protocol_fee
+        ),
+    );
+    Ok(DistributeRewardsResponse {
+        rewards,
+        protocol_fee,
+        fee,
+        distributed,
+    })
+}
+
+#[update]
+fn set_protocol_fee_config(config: ProtocolFeeConfig) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    if config.reward_fee_bps > BPS_DENOMINATOR || config.withdrawal_fee_bps > BPS_DENOMINATOR {
+        return Err(ConfigError::InvalidConfig(format!(
+            "fees must not exceed {} basis points",
+            BPS_DENOMINATOR
+        )));
+    }
+    state::mutate_state(|state| state.protocol_fee_config = config);
+    Ok(())
+}
+
+#[query]
+fn get_protocol_fee_config() -> ProtocolFeeConfig {
+    state::read_state(|state| state.protocol_fee_config.clone())
+}
+
+/// Get the amount of ckBTC accrued in the treasury subaccount.
+#[query]
+fn get_treasury_balance() -> u64 {
+    state::read_state(|state| state.treasury_balance)
+}
+
+/// Withdraw ckBTC from the treasury subaccount, only callable by the treasury owner.
+///
+/// The ckBTC ledger fee is paid by the treasury, on top of the withdrawn amount.
+#[update]
+async fn withdraw_treasury(
+    args: WithdrawTreasuryArgs,
+) -> Result<WithdrawTreasuryResponse, WithdrawTreasuryError> {
+    let (treasury_owner, treasury_balance, fee) = state::read_state(|state| {
+        (
+            state.protocol_fee_config.treasury_owner,
+            state.treasury_balance,
+            state.ckbtc_transfer_fee,
+        )
+    });
+    if treasury_owner != Some(ic_cdk::caller()) {
+        return Err(WithdrawTreasuryError::NotTreasuryOwner);
+    }
+    if treasury_balance < args.amount.saturating_add(fee) {
+        return Err(WithdrawTreasuryError::NotEnoughTreasuryBalance);
+    }
+    // Reserve the amount, so that a concurrent call cannot withdraw it again.
+    let nonce = state::mutate_state(|state| {
+        state.treasury_balance -= args.amount + fee;
+        certification::certify_pool(state);
+        journal::next_operation_id(state)
+    });
+    let transfer = PoolTransfer {
+        kind: PoolTransferKind::WithdrawTreasury,
+        from_subaccount: Some(treasury_subaccount()),
+        to: Account {
+            owner: args.to,
+            subaccount: args.to_subaccount,
+        },
+        amount: args.amount,
+        fee,
+        memo: ledger::memo(MemoAction::WithdrawTreasury, nonce),
+        created_at: time(),
+    };
+    // The reserved amount is released if the ledger rejects the transfer.
+    match journal::run_pool_transfer(nonce, transfer).await {
+        PoolTransferOutcome::Completed => {
+            log::info(
+                "withdraw_treasury",
+                format!("withdrew {} ckBTC to {}", args.amount, args.to),
+            );
+            Ok(WithdrawTreasuryResponse {
+                amount: args.amount,
+                fee,
+            })
+        }
+        PoolTransferOutcome::Rejected(e) => Err(WithdrawTreasuryError::CkbtcTransferError(e)),
+        PoolTransferOutcome::Pending(reason) => {
+            Err(WithdrawTreasuryError::TransferPending { nonce, reason })
+        }
+    }
+}
+
+#[update]
+fn set_minimum_amounts(minimum_amounts: MinimumAmounts) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    state::mutate_state(|state| state.minimum_amounts = minimum_amounts);
+    Ok(())
+}
+
+/// Get the minimum amounts of the operations, as they are enforced.
+#[query]
+fn get_minimum_amounts() -> MinimumAmounts {
+    state::read_state(effective_minimum_amounts)
+}
+
+/// Sync the minimum withdrawal amount of the ckBTC minter, and the transfer fees of the ckBTC
+/// and otBTC ledgers.
+///
+/// This is also done on initialization, after an upgrade and once a day.
+#[update]
+async fn sync_minter_info() -> Result<MinterInfo, SyncMinterInfoError> {
+    ensure_controller().map_err(|_| SyncMinterInfoError::NotController)?;
+    let minter_info = fetch_minter_info().await?;
+    fetch_ledger_fees().await?;
+    Ok(minter_info)
+}
+
+async fn sync_minter_info_periodically() {
+    if cycles::check_cycles_balance() {
+        return;
+    }
+    if let Err(e) = fetch_minter_info().await {
+        log::warn("sync_minter_info", format!("{:?}", e));
+    }
+    if let Err(e) = fetch_ledger_fees().await {
+        log::warn("sync_ledger_fees", format!("{:?}", e));
+    }
+}
+
+/// Sync the transfer fees of the ckBTC and otBTC ledgers, which are passed with every transfer.
+async fn fetch_ledger_fees() -> Result<(), SyncMinterInfoError> {
+    let (ckbtc_ledger_account, otbtc_ledger_account) =
+        state::read_state(|state| (state.ckbtc_ledger_account, state.otbtc_ledger_account));
+    let ckbtc_transfer_fee = ledger::fee(ckbtc_ledger_account).await.map_err(|e| {
+        SyncMinterInfoError::LedgerError(format!("failed to call ckBTC ledger: {:?}", e))
+    })?;
+    let otbtc_transfer_fee = ledger::fee(otbtc_ledger_account).await.map_err(|e| {
+        SyncMinterInfoError::LedgerError(format!("failed to call otBTC ledger: {:?}", e))
+    })?;
+    state::mutate_state(|state| {
+        state.ckbtc_transfer_fee = ckbtc_transfer_fee;
+        state.otbtc_transfer_fee = otbtc_transfer_fee;
+    });
+    Ok(())
+}
+
+async fn fetch_minter_info() -> Result<MinterInfo, SyncMinterInfoError> {
+    let ckbtc_minting_account = state::read_state(|state| state.ckbtc_minting_account);
+    let (minter_info,): (MinterInfo,) = ic_cdk::call(ckbtc_minting_account, "get_minter_info", ())
+        .await
+        .map_err(|e| {
+            SyncMinterInfoError::CkbtcMinterError(format!("failed to call ckBTC minter: {:?}", e))
+        })?;
+    state::mutate_state(|state| {
+        state.retrieve_btc_min_amount = minter_info.retrieve_btc_min_amount;
+    });
+    Ok(minter_info)
+}
+
+#[update]
+fn set_staking_caps(staking_caps: StakingCaps) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    state::mutate_state(|state| state.staking_caps = staking_caps);
+    Ok(())
+}
+
+#[query]
+fn get_staking_caps() -> StakingCaps {
+    state::read_state(|state| state.staking_caps.clone())
+}
+
+#[update]
+fn add_to_staker_allowlist(eth_addresses: Vec<String>) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    let eth_addresses = eth_addresses
+        .iter()
+        .map(|eth_address| {
+            canonical_staker_address(eth_address).ok_or_else(|| {
+                ConfigError::InvalidConfig(format!(
+                    "invalid Ethereum or Bitcoin address: {}",
+                    eth_address
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    state::mutate_state(|state| state.staker_allowlist.extend(eth_addresses));
+    Ok(())
+}
+
+#[update]
+fn remove_from_staker_allowlist(eth_addresses: Vec<String>) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    let eth_addresses: Vec<String> = eth_addresses
+        .iter()
+        .filter_map(|a| canonical_staker_address(a))
+        .collect();
+    state::mutate_state(|state| {
+        for eth_address in eth_addresses {
+            state.staker_allowlist.remove(&eth_address);
+        }
+    });
+    Ok(())
+}
+
+#[query]
+fn get_staker_allowlist() -> Vec<String> {
+    state::read_state(|state| state.staker_allowlist.iter().cloned().collect())
+}
+
+#[query]
+fn get_operation(operation_id: u64) -> Option<Operation> {
+    state::read_state(|state| state.operations.get(&operation_id).cloned())
+}
+
+/// Resume an operation which failed at a step that can neither be retried nor compensated
+/// automatically, once a controller has checked on the ledger whether the transfer of the step
+/// (found by the memo of the operation) went through.
+///
+/// The step is completed if `transferred`, or retried as a new transfer otherwise.
+#[update]
+async fn resolve_operation(
+    operation_id: u64,
+    transferred: bool,
+) -> Result<Operation, ResolveOperationError> {
+    ensure_controller().map_err(|_| ResolveOperationError::NotController)?;
+    state::mutate_state(|state| {
+        let op = state
+            .operations
+            .get(&operation_id)
+            .ok_or(ResolveOperationError::OperationNotFound)?;
+        if !op.needs_operator {
+            return Err(ResolveOperationError::NotLeftToOperator);
+        }
+        if journal::locked_stakers(op)
+            .any(|eth_address| state.staker_locks.contains_key(eth_address))
+        {
+            return Err(ResolveOperationError::StakerBusy);
+        }
+        journal::resolve_operation(state, operation_id, transferred);
+        Ok(())
+    })?;
+    let _ = journal::run(operation_id).await;
+    Ok(state::read_state(|state| {
+        state.operations[&operation_id].clone()
+    }))
+}
+
+/// Get the recent operations of a staker, including the unfinished ones.
+#[query]
+fn get_staker_operations(eth_address: String) -> Vec<Operation> {
+    let Some(eth_address) = canonical_staker_address(&eth_address) else {
+        return vec![];
+    };
+    state::read_state(|state| {
+        state
+            .operations
+            .values()
+            .filter(|op| op.eth_address == eth_address)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Get the balances of a staker and the pool totals, with a witness of their certification.
+#[query]
+fn get_staker(eth_address: String) -> GetStakerResponse {
+    // An invalid address is looked up as given, and proven absent.
+    let eth_address = canonical_staker_address(&eth_address).unwrap_or(eth_address);
+    state::read_state(|state| GetStakerResponse {
+        balances: state
+            .stakers_map
+            .get(&eth_address)
+            .map(|staker| StakerBalances {
+                ckbtc_balance: staker.ckbtc_balance,
+                otbtc_balance: staker.otbtc_balance,
+            }),
+        total_ckbtc_in_pool: state.total_ckbtc_in_pool,
+        treasury_balance: state.treasury_balance,
+        certificate: ic_cdk::api::data_certificate().map(ByteBuf::from),
+        witness: ByteBuf::from(certification::staker_witness(&eth_address)),
+        eth_address,
+    })
+}
+
+#[query]
+fn get_cycles_balance() -> CyclesBalance {
+    state::read_state(|state| CyclesBalance {
+        balance: ic_cdk::api::canister_balance128(),
+        low_water_mark: state.cycles_low_water_mark,
+    })
+}
+
+/// Set the cycles balance below which the automatic deposit scanning and minter info sync
+/// are paused.
+#[update]
+fn set_cycles_low_water_mark(low_water_mark: u128) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    state::mutate_state(|state| state.cycles_low_water_mark = low_water_mark);
+    cycles::check_cycles_balance();
+    Ok(())
+}
+
+/// Get the recent logs of the canister, oldest first.
+#[query]
+fn get_logs(args: GetLogsArgs) -> Vec<LogEntry> {
+    log::get_logs(args.min_level, args.since)
+}
+
+/// Serve the metrics of the pool at `/metrics`, the logs at `/logs`, and the read-only JSON API
+/// under `/api/`.
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    http::serve(req)
+}
+
+/// Set the EVM RPC canister used to verify the signatures of smart contract wallets, or
+/// disable them with `None`.
+#[update]
+fn set_eip1271_config(eip1271_config: Option<Eip1271Config>) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    state::mutate_state(|state| state.eip1271_config = eip1271_config);
+    Ok(())
+}
+
+#[query]
+fn get_eip1271_config() -> Option<Eip1271Config> {
+    state::read_state(|state| state.eip1271_config.clone())
+}
+
+/// Register an Ethereum address as a smart contract wallet, whose signatures are checked by the
+/// contract, or unregister it.
+#[update]
+fn set_contract_wallet(eth_address: String, is_contract_wallet: bool) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    let invalid_address = || {
+        ConfigError::InvalidConfig(
+            "only Ethereum addresses can be smart contract wallets".to_string(),
+        )
+    };
+    let eth_address = siwe::normalize_eth_address(&eth_address).ok_or_else(invalid_address)?;
+    let subaccount =
+        convert_staker_address_to_subaccount(&eth_address).map_err(|_| invalid_address())?;
+    state::mutate_state(|state| {
+        state
+            .stakers_map
+            .entry(eth_address.clone())
+            .or_insert_with(|| Staker::new(eth_address.clone(), subaccount))
+            .is_contract_wallet = is_contract_wallet;
+        if !is_contract_wallet {
+            state
+                .eip1271_last_checks
+                .retain(|(_, checked_address), _| *checked_address != eth_address);
+        }
+        certification::certify_staker(state, &eth_address);
+    });
+    Ok(())
+}
+
+#[update]
+fn set_siwe_config(siwe_config: SiweConfig) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    if siwe_config.domain.is_empty() || siwe_config.uri.is_empty() {
+        return Err(ConfigError::InvalidConfig(
+            "the domain and the URI must not be empty".to_string(),
+        ));
+    }
+    if siwe_config.session_duration == 0 {
+        return Err(ConfigError::InvalidConfig(
+            "the session duration must be positive".to_string(),
+        ));
+    }
+    state::mutate_state(|state| state.siwe_config = Some(siwe_config));
+    Ok(())
+}
+
+#[query]
+fn get_siwe_config() -> Option<SiweConfig> {
+    state::read_state(|state| state.siwe_config.clone())
+}
+
+/// Prepare the EIP-4361 message which the owner of an Ethereum address signs to open a session
+/// for the caller.
+///
+/// The message must be signed and passed to `siwe_login` by the caller within 5 minutes. A new
+/// call replaces the pending message of the caller.
+#[update]
+fn prepare_siwe_login(eth_address: String) -> Result<String, SiweError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(SiweError::AnonymousCaller);
+    }
+    let eth_address =
+        siwe::normalize_eth_address(&eth_address).ok_or(SiweError::InvalidEthereumAddress)?;
+    let now = time();
+    state::mutate_state(|state| {
+        let config = state.siwe_config.as_ref().ok_or(SiweError::NotConfigured)?;
+        let session_expires_at = now.saturating_add(config.session_duration);
+        let nonce_preimage = [caller.as_slice(), &now.to_be_bytes()].concat();
+        let nonce = hex::encode(&keccak256(&nonce_preimage)[..8]);
+        let message = siwe::login_message(
+            config,
+            &eth_address,
+            &caller,
+            &nonce,
+            now,
+            session_expires_at,
+        );
+        state
+            .siwe_logins
+            .retain(|_, login| now < login.login_deadline);
+        state.siwe_logins.insert(
+            caller,
+            SiweLogin {
+                eth_address,
+                message: message.clone(),
+                login_deadline: now + SIWE_LOGIN_DEADLINE,
+                session_expires_at,
+            },
+        );
+        Ok(message)
+    })
+}
+
+/// Open a session for the caller with the EIP-191 signature of the message prepared by
+/// `prepare_siwe_login`.
+///
+/// Until the session expires or is revoked, the `_for_caller` endpoints called by the caller
+/// act for the Ethereum address without signatures.
+#[update]
+fn siwe_login(signature: Vec<u8>) -> Result<SiweSession, SiweError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let login = state::mutate_state(|state| state.siwe_logins.remove(&caller))
+        .ok_or(SiweError::NoPendingLogin)?;
+    if now >= login.login_deadline {
+        return Err(SiweError::LoginExpired);
+    }
+    let signer = siwe::recover_personal_sign_address(&login.message, &signature)
+        .map_err(SiweError::InvalidSignature)?;
+    if signer != login.eth_address {
+        return Err(SiweError::InvalidSignature(
+            VerifySignatureError::SignerAddressMismatch,
+        ));
+    }
+    let session = SiweSession {
+        eth_address: login.eth_address,
+        expires_at: login.session_expires_at,
+    };
+    state::mutate_state(|state| {
+        state
+            .siwe_sessions
+            .retain(|_, session| now < session.expires_at);
+        state.siwe_sessions.insert(caller, session.clone());
+    });
+    log::info(
+        "siwe_login",
+        format!("{} signed in as {}", caller, session.eth_address),
+    );
+    Ok(session)
+}
+
+/// Close the session of the caller.
+#[update]
+fn revoke_siwe_session() -> Result<(), SiweError> {
+    let caller = ic_cdk::caller();
+    let session = state::mutate_state(|state| state.siwe_sessions.remove(&caller))
+        .ok_or(SiweError::NoSession)?;
+    log::info(
+        "revoke_siwe_session",
+        format!("{} signed out of {}", caller, session.eth_address),
+    );
+    Ok(())
+}
+
+/// Get the active session of the caller.
+#[query]
+fn get_siwe_session() -> Option<SiweSession> {
+    let now = time();
+    state::read_state(|state| {
+        state
+            .siwe_sessions
+            .get(&ic_cdk::caller())
+            .filter(|session| now < session.expires_at)
+            .cloned()
+    })
+}
+
+/// Set the name of the threshold ECDSA key signing Ethereum messages, and return the Ethereum
+/// address of the pool derived from it.
+#[update]
+async fn set_eth_signer_key_name(key_name: String) -> Result<String, ConfigError> {
+    ensure_controller()?;
+    let public_key = eth_signer::fetch_public_key(&key_name)
+        .await
+        .map_err(ConfigError::InvalidConfig)?;
+    let address = eth_signer::eth_address(&public_key).map_err(ConfigError::InvalidConfig)?;
+    state::mutate_state(|state| {
+        state.eth_signer_key_name = Some(key_name);
+        state.eth_signer_public_key = Some(public_key);
+        // The attestations signed by another key are not valid anymore.
+        state.attestations.clear();
+    });
+    Ok(format!("0x{}", hex::encode(address)))
+}
+
+/// Get the Ethereum address of the pool which signs Ethereum messages, if configured.
+#[query]
+fn get_eth_signer_address() -> Option<String> {
+    state::read_state(|state| {
+        let public_key = state.eth_signer_public_key.as_ref()?;
+        let address = eth_signer::eth_address(public_key).ok()?;
+        Some(format!("0x{}", hex::encode(address)))
+    })
+}
+
+/// Set the EIP-712 domain of the stake attestations, or disable them with `None`.
+#[update]
+fn set_attestation_config(
+    attestation_config: Option<AttestationConfig>,
+) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    let attestation_config = match attestation_config {
+        Some(config) => Some(AttestationConfig {
+            verifying_contract: siwe::normalize_eth_address(&config.verifying_contract)
+                .ok_or_else(|| {
+                    ConfigError::InvalidConfig("invalid verifying contract address".to_string())
+                })?,
+            ..config
+        }),
+        None => None,
+    };
+    state::mutate_state(|state| {
+        state.attestation_config = attestation_config;
+        // The attestations of another domain are not valid anymore.
+        state.attestations.clear();
+    });
+    Ok(())
+}
+
+#[query]
+fn get_attestation_config() -> Option<AttestationConfig> {
+    state::read_state(|state| state.attestation_config.clone())
+}
+
+/// Get an attestation of the otBTC balance of an Ethereum address, signed by the threshold ECDSA
+/// key of the pool so that it can be checked by the Ethereum contract of the attestation domain.
+///
+/// Only the staker, through a Sign-In with Ethereum session, and the controllers can get an
+/// attestation. The latest attestation of the address is returned again while it is recent and
+/// the balance is unchanged, and none is signed while the cycles balance is low, which bounds
+/// the signing costs.
+#[update]
+async fn get_stake_attestation(eth_address: String) -> Result<StakeAttestation, AttestationError> {
+    let eth_address = siwe::normalize_eth_address(&eth_address)
+        .ok_or(AttestationError::InvalidEthereumAddress)?;
+    let address: [u8; 20] = hex::decode(&eth_address)
+        .ok()
+        .and_then(|address| address.try_into().ok())
+        .ok_or(AttestationError::InvalidEthereumAddress)?;
+    if caller_account().as_ref() != Some(&eth_address) && ensure_controller().is_err() {
+        return Err(AttestationError::NotAuthorized);
+    }
+    let now = time();
+    let (key_name, public_key, config, otbtc_balance, latest) = state::read_state(|state| {
+        let staker = state
+            .stakers_map
+            .get(&eth_address)
+            .ok_or(AttestationError::LackOfStakerRecord)?;
+        let (Some(key_name), Some(public_key), Some(config)) = (
+            &state.eth_signer_key_name,
+            &state.eth_signer_public_key,
+            &state.attestation_config,
+        ) else {
+            return Err(AttestationError::NotConfigured);
+        };
+        let latest = state.attestations.get(&eth_address).filter(|attestation| {
+            attestation.otbtc_balance == staker.otbtc_balance
+                && now < attestation.timestamp * 1_000_000_000 + ATTESTATION_REUSE_PERIOD
+        });
+        Ok((
+            key_name.clone(),
+            public_key.clone(),
+            config.clone(),
+            staker.otbtc_balance,
+            latest.cloned(),
+        ))
+    })?;
+    if let Some(attestation) = latest {
+        return Ok(attestation);
+    }
+    if cycles::check_cycles_balance() {
+        return Err(AttestationError::CyclesBalanceLow);
+    }
+    let nonce = state::mutate_state(|state| {
+        state.next_attestation_nonce += 1;
+        state.next_attestation_nonce - 1
+    });
+    let timestamp = now / 1_000_000_000;
+    let message = attestation::message(&address, otbtc_balance, nonce, timestamp);
+    let digest = attestation::digest(&config, &message);
+    let signature = eth_signer::sign_digest(&key_name, &public_key, digest)
+        .await
+        .map_err(|e| {
+            log::warn("get_stake_attestation", e.clone());
+            AttestationError::SigningFailed(e)
+        })?;
+    let attestation = StakeAttestation {
+        eth_address: eth_address.clone(),
+        otbtc_balance,
+        nonce,
+        timestamp,
+        message,
+        digest: digest.to_vec(),
+        signature,
+    };
+    state::mutate_state(|state| state.attestations.insert(eth_address, attestation.clone()));
+    Ok(attestation)
+}
+
+/// Configure the lock-and-mint bridge of otBTC to an ERC-20 contract on Ethereum, or disable it.
+#[update]
+fn set_bridge_config(bridge_config: Option<BridgeConfig>) -> Result<(), ConfigError> {
+    ensure_controller()?;
+    let bridge_config = match bridge_config {
+        Some(config) => Some(BridgeConfig {
+            erc20_contract: siwe::normalize_eth_address(&config.erc20_contract).ok_or_else(
+                || ConfigError::InvalidConfig("invalid ERC-20 contract address".to_string()),
+            )?,
+            ..config
+        }),
+        None => None,
+    };
+    state::mutate_state(|state| state.bridge_config = bridge_config);
+    Ok(())
+}
+
+#[query]
+fn get_bridge_config() -> Option<BridgeConfig> {
+    state::read_state(|state| state.bridge_config.clone())
+}
+
+/// Get the amount of otBTC locked in the bridge, which bounds the ERC-20 tokens on Ethereum.
+#[query]
+fn get_bridged_otbtc() -> u64 {
+    state::read_state(|state| state.bridged_otbtc)
+}
+
+/// Lock otBTC of an Ethereum staker in the bridge subaccount, and return a voucher signed by the
+/// threshold ECDSA key of the pool which mints the amount on the ERC-20 contract.
+///
+/// The signed message is `<nonce>:bridge_otbtc_to_eth:<amount>`.
+#[update]
+async fn bridge_otbtc_to_eth(
+    args: BridgeOtbtcArgs,
+) -> Result<BridgeOtbtcResponse, BridgeOtbtcError> {
+    bridge_otbtc_to_eth_for(
+        args.eth_address,
+        args.amount,
+        Authorization::Signature(&args.signature),
+    )
+    .await
+}
+
+/// Lock otBTC of the Ethereum address signed in by the caller, see `bridge_otbtc_to_eth`.
+#[update]
+async fn bridge_otbtc_to_eth_for_caller(
+    amount: u64,
+) -> Result<BridgeOtbtcResponse, BridgeOtbtcError> {
+    let eth_address = caller_account().ok_or(BridgeOtbtcError::AnonymousCaller)?;
+    bridge_otbtc_to_eth_for(eth_address, amount, Authorization::Caller).await
+}
+
+async fn bridge_otbtc_to_eth_for(
+    eth_address: String,
+    amount: u64,
+    authorization: Authorization<'_>,
+) -> Result<BridgeOtbtcResponse, BridgeOtbtcError> {
+    // Only Ethereum addresses can receive the ERC-20 tokens.
+    let eth_address = siwe::normalize_eth_address(&eth_address)
+        .ok_or(BridgeOtbtcError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
+        .map_err(|_| BridgeOtbtcError::InvalidEthereumAddress)?;
+    let (staker, fee) = state::read_state(|state| {
+        if state.bridge_config.is_none() || state.eth_signer_public_key.is_none() {
+            return Err(BridgeOtbtcError::NotConfigured);
+        }
+        let staker = state
+            .stakers_map
+            .get(&eth_address)
+            .ok_or(BridgeOtbtcError::LackOfStakerRecord)?;
+        Ok((staker.clone(), state.otbtc_transfer_fee))
+    })?;
+    let min = state::read_state(|state| effective_minimum_amounts(state).bridge_otbtc);
+    if amount < min {
+        return Err(BridgeOtbtcError::AmountTooLow { min });
+    }
+    if staker.otbtc_balance < amount.saturating_add(fee) {
+        return Err(BridgeOtbtcError::NotEnoughOtbtcBalance);
+    }
+    if authorize(&staker, "bridge_otbtc_to_eth", amount, None, &authorization)
+        .await
+        .is_err()
+    {
+        return Err(BridgeOtbtcError::InvalidSignature);
+    }
+    // Record the operation, which transfers otBTC tokens from the subaccount of the staker to
+    // the bridge subaccount and then records the voucher.
+    let operation_id = state::mutate_state(|state| {
+        let Some(config) = &state.bridge_config else {
+            return Err(BridgeOtbtcError::NotConfigured);
+        };
+        let destination = Destination::Bridge {
+            erc20_contract: config.erc20_contract.clone(),
+            chain_id: config.chain_id,
+        };
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(BridgeOtbtcError::StakerBusy);
+        }
+        if !nonce_is_current(state, &staker, &authorization) {
+            return Err(BridgeOtbtcError::InvalidSignature);
+        }
+        let nonce = consume_nonce(state, &eth_address);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::BridgeOtbtc,
+                eth_address: &eth_address,
+                subaccount,
+                amount,
+                fee,
+                protocol_fee: 0,
+                destination: Some(destination),
+                nonce: Some(nonce),
+            },
+        ))
+    })?;
+    let op = match journal::run(operation_id).await {
+        Outcome::Completed(op) => op,
+        Outcome::Failed(error) => return Err(error.into()),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => {
+            return Err(BridgeOtbtcError::OperationPending {
+                operation_id,
+                reason,
+            })
+        }
+    };
+    match bridge::sign_voucher(operation_id).await {
+        Ok(Some(voucher)) => Ok(BridgeOtbtcResponse {
+            operation_id,
+            fee: op.fee,
+            voucher,
+        }),
+        Ok(None) => Err(BridgeOtbtcError::VoucherNotSigned {
+            operation_id,
+            reason: "voucher not found, should not happen".to_string(),
+        }),
+        Err(reason) => {
+            log::warn("bridge_otbtc_to_eth", reason.clone());
+            Err(BridgeOtbtcError::VoucherNotSigned {
+                operation_id,
+                reason,
+            })
+        }
+    }
+}
+
+/// Get the mint voucher of a bridge operation, signing it if it is not signed yet.
+#[update]
+async fn get_bridge_voucher(nonce: u64) -> Result<BridgeVoucher, BridgeVoucherError> {
+    match bridge::sign_voucher(nonce).await {
+        Ok(Some(voucher)) => Ok(voucher),
+        Ok(None) => Err(BridgeVoucherError::VoucherNotFound),
+        Err(e) => {
+            log::warn("get_bridge_voucher", e.clone());
+            Err(BridgeVoucherError::SigningFailed(e))
+        }
+    }
+}
+
+/// Unlock otBTC from the bridge subaccount into the subaccount of a staker, for a burn of the
+/// ERC-20 tokens on Ethereum reported by the operator of the bridge.
+///
+/// Each burn is redeemed once, and the otBTC ledger fee is paid out of the burnt amount.
+#[update]
+async fn redeem_from_eth(
+    burn_proof: BurnProof,
+) -> Result<RedeemFromEthResponse, RedeemFromEthError> {
+    let eth_address = siwe::normalize_eth_address(&burn_proof.eth_address)
+        .ok_or(RedeemFromEthError::InvalidEthereumAddress)?;
+    let subaccount = convert_staker_address_to_subaccount(&eth_address)
+        .map_err(|_| RedeemFromEthError::InvalidEthereumAddress)?;
+    let tx_hash = burn_proof.tx_hash.to_lowercase();
+    let tx_hash = tx_hash.strip_prefix("0x").unwrap_or(&tx_hash).to_string();
+    let operation_id = state::mutate_state(|state| {
+        let config = state
+            .bridge_config
+            .as_ref()
+            .ok_or(RedeemFromEthError::NotConfigured)?;
+        if ic_cdk::caller() != config.operator {
+            return Err(RedeemFromEthError::NotOperator);
+        }
+        let burn = (tx_hash.clone(), burn_proof.log_index);
+        if state.redeemed_burns.contains(&burn) {
+            return Err(RedeemFromEthError::AlreadyRedeemed);
+        }
+        if burn_proof.amount > state.bridged_otbtc {
+            return Err(RedeemFromEthError::ExceedsBridgedAmount);
+        }
+        if state.staker_locks.contains_key(&eth_address) {
+            return Err(RedeemFromEthError::StakerBusy);
+        }
+        state
+            .stakers_map
+            .entry(eth_address.clone())
+            .or_insert_with(|| Staker::new(eth_address.clone(), subaccount));
+        certification::certify_staker(state, &eth_address);
+        state.redeemed_burns.insert(burn);
+        // Deducted now so that concurrent redemptions cannot unlock more than is locked.
+        state.bridged_otbtc -= burn_proof.amount;
+        let fee = state.otbtc_transfer_fee.min(burn_proof.amount);
+        Ok(journal::create_operation(
+            state,
+            OperationRequest {
+                kind: OperationKind::RedeemOtbtc,
+                eth_address: &eth_address,
+                subaccount,
+                amount: burn_proof.amount,
+                fee,
+                protocol_fee: 0,
+                destination: None,
+                nonce: None,
+            },
+        ))
+    })?;
+    log::info(
+        "redeem_from_eth",
+        format!(
+            "redeeming burn 0x{}:{} of {} for {}",
+            tx_hash, burn_proof.log_index, burn_proof.amount, eth_address
+        ),
+    );
+    match journal::run(operation_id).await {
+        Outcome::Completed(op) => Ok(RedeemFromEthResponse {
+            operation_id,
+            amount: op.amount - op.fee,
+            fee: op.fee,
+        }),
+        Outcome::Failed(error) => Err(RedeemFromEthError::UnlockFailed {
+            operation_id,
+            reason: format!("{:?}", error),
+        }),
+        Outcome::Pending {
+            operation_id,
+            reason,
+        } => Err(RedeemFromEthError::OperationPending {
+            operation_id,
+            reason,
+        }),
+    }
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subaccount_preimages_cannot_collide() {
+        let eth_preimage =
+            eth_address_preimage("7e5f4552091a69125d5dfcb7b8c2659029395bdf").unwrap();
+        assert_eq!(eth_preimage.len(), 20);
+        // The shortest preimages, of an empty payload, are longer than an Ethereum address.
+        for tag in [PRINCIPAL_SUBACCOUNT_TAG, BTC_SUBACCOUNT_TAG] {
+            assert!(tagged_preimage(tag, &[]).len() > 20);
+        }
+        // A 10 bytes principal, such as a canister id, has a 20 bytes preimage without the tags.
+        let canister_id = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+        assert_eq!(canister_id.as_slice().len(), 10);
+        let principal_preimage = tagged_preimage(PRINCIPAL_SUBACCOUNT_TAG, canister_id.as_slice());
+        assert!(principal_preimage.len() > 20);
+        // The tags are distinct and neither is a prefix of the other.
+        assert!(!PRINCIPAL_SUBACCOUNT_TAG.starts_with(BTC_SUBACCOUNT_TAG));
+        assert!(!BTC_SUBACCOUNT_TAG.starts_with(PRINCIPAL_SUBACCOUNT_TAG));
+        let btc_preimage = tagged_preimage(BTC_SUBACCOUNT_TAG, canister_id.as_slice());
+        assert_ne!(principal_preimage, btc_preimage);
+        // The payload is prefixed with its length.
+        assert_eq!(
+            tagged_preimage(BTC_SUBACCOUNT_TAG, b"ab"),
+            b"btc_staking_pool:btc:\x02ab".to_vec()
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use alloc::collections::VecDeque;
+use candid::{CandidType, Deserialize};
+use core::cell::RefCell;
+use serde::Serialize;
+
+/// The maximum number of log entries kept, the oldest entries are dropped first.
+const MAX_LOG_ENTRIES: usize = 1000;
+
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LogEntry {
+    /// The time of the entry, in nano seconds.
+    pub timestamp: u64,
+    pub level: LogLevel,
+    /// What the entry is about, such as an endpoint or an operation.
+    pub context: String,
+    pub message: String,
+}
+
+thread_local! {
+    static __LOGS: RefCell<VecDeque<LogEntry>> = RefCell::default();
+}
+
+/// Append an entry to the logs, and print it to the debug log of the canister.
+///
+/// The entries logged in a query call are discarded with the rest of its changes.
+pub fn log(level: LogLevel, context: &str, message: String) {
+    let timestamp = crate::time();
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::println!("{} {:?} [{}] {}", timestamp, level, context, message);
+    __LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        if logs.len() == MAX_LOG_ENTRIES {
+            logs.pop_front();
+        }
+        logs.push_back(LogEntry {
+            timestamp,
+            level,
+            context: context.to_string(),
+            message,
+        });
+    });
+}
+
+pub fn info(context: &str, message: String) {
+    log(LogLevel::Info, context, message);
+}
+
+pub fn warn(context: &str, message: String) {
+    log(LogLevel::Warn, context, message);
+}
+
+pub fn error(context: &str, message: String) {
+    log(LogLevel::Error, context, message);
+}
+
+/// Get the entries at or above a level, logged at or after a time, oldest first.
+pub fn get_logs(min_level: Option<LogLevel>, since: Option<u64>) -> Vec<LogEntry> {
+    __LOGS.with(|logs| {
+        logs.borrow()
+            .iter()
+            .filter(|entry| min_level.is_none_or(|level| entry.level >= level))
+            .filter(|entry| since.is_none_or(|since| entry.timestamp >= since))
+            .cloned()
+            .collect()
+    })
+}
@@ -0,0 +1,98 @@
+use crate::state::{self, OperationKind};
+use ic_metrics_encoder::MetricsEncoder;
+
+fn operation_label(kind: OperationKind) -> &'static str {
+    match kind {
+        OperationKind::Stake => "stake",
+        OperationKind::Unstake => "unstake",
+        OperationKind::UnlockTokens => "unlock_tokens",
+        OperationKind::WithdrawBtc => "withdraw_btc",
+        OperationKind::TransferOtbtc => "transfer_otbtc",
+        OperationKind::ExportOtbtc => "export_otbtc",
+        OperationKind::WithdrawCkbtc => "withdraw_ckbtc",
+        OperationKind::BridgeOtbtc => "bridge_otbtc",
+        OperationKind::RedeemOtbtc => "redeem_otbtc",
+        OperationKind::DistributeRewards => "distribute_rewards",
+    }
+}
+
+/// Encode the metrics of the pool in the Prometheus text format.
+pub fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
+    let now = crate::time();
+    state::read_state(|state| {
+        w.encode_gauge(
+            "btc_staking_pool_total_ckbtc_in_pool",
+            state.total_ckbtc_in_pool as f64,
+            "Total amount of ckBTC staked in the pool, in satoshis.",
+        )?;
+        w.encode_gauge(
+            "btc_staking_pool_stakers",
+            state.stakers_map.len() as f64,
+            "Number of stakers.",
+        )?;
+        w.encode_gauge(
+            "btc_staking_pool_unstaking_queue_length",
+            state.unstaking_queue.len() as f64,
+            "Number of unstake requests waiting to be unlocked.",
+        )?;
+        // The age of the oldest unstake request whose unlock time has been reached.
+        let oldest_pending_unlock_age = state
+            .unstaking_queue
+            .iter()
+            .map(|request| request.unlock_time)
+            .min()
+            .map_or(0, |unlock_time| now.saturating_sub(unlock_time));
+        w.encode_gauge(
+            "btc_staking_pool_oldest_pending_unlock_age_seconds",
+            oldest_pending_unlock_age as f64 / 1e9,
+            "Time since the oldest unstake request could be unlocked, in seconds.",
+        )?;
+        w.encode_gauge(
+            "btc_staking_pool_cycle_balance",
+            ic_cdk::api::canister_balance128() as f64,
+            "Cycles balance of the canister.",
+        )?;
+        w.encode_gauge(
+            "btc_staking_pool_cycles_low_water_mark",
+            state.cycles_low_water_mark as f64,
+            "Cycles balance below which the non-essential work is paused.",
+        )?;
+        w.encode_gauge(
+            "btc_staking_pool_cycles_low",
+            if state.cycles_low { 1.0 } else { 0.0 },
+            "Whether the cycles balance is below the low-water mark, pausing the non-essential work.",
+        )?;
+
+        let mut succeeded = w.counter_vec(
+            "btc_staking_pool_operations_succeeded",
+            "Number of operations completed successfully.",
+        )?;
+        for (kind, metrics) in &state.operation_metrics {
+            succeeded = succeeded.value(
+                &[("operation", operation_label(*kind))],
+                metrics.succeeded as f64,
+            )?;
+        }
+        let mut failed = w.counter_vec(
+            "btc_staking_pool_operations_failed",
+            "Number of operations which failed or were compensated.",
+        )?;
+        for (kind, metrics) in &state.operation_metrics {
+            failed = failed.value(
+                &[("operation", operation_label(*kind))],
+                metrics.failed as f64,
+            )?;
+        }
+        let mut last_error_time = w.gauge_vec(
+            "btc_staking_pool_operation_last_error_timestamp_seconds",
+            "Time of the last failed step of an operation, in seconds since the epoch.",
+        )?;
+        for (kind, metrics) in &state.operation_metrics {
+            if let Some(time) = metrics.last_error_time {
+                last_error_time = last_error_time
+                    .value(&[("operation", operation_label(*kind))], time as f64 / 1e9)?;
+            }
+        }
+        Ok(())
+    })
+}
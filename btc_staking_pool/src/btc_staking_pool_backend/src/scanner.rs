@@ -0,0 +1,138 @@
+use crate::state::{self, DepositScan};
+use core::{cell::Cell, time::Duration};
+
+const SCAN_TIMER_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_SCAN_BACKOFF: u64 = 60 * 10 * 1000000000; // 10 minutes, in nano seconds
+const MAX_SCAN_BACKOFF: u64 = 60 * 60 * 4 * 1000000000; // 4 hours, in nano seconds
+const MAX_SCANS_PER_ROUND: usize = 10;
+/// The number of registered addresses up to which the addresses of unauthenticated callers are
+/// registered.
+const MAX_UNAUTHENTICATED_SCANS: usize = 1_000;
+/// The maximum number of addresses registered by unauthenticated callers per
+/// `SCAN_TIMER_INTERVAL`.
+const MAX_UNAUTHENTICATED_REGISTRATIONS: u32 = 10;
+
+thread_local! {
+    static __SCANNING: Cell<bool> = Cell::new(false);
+    /// The start of the current interval, and the number of addresses registered by
+    /// unauthenticated callers in it.
+    static __UNAUTHENTICATED_REGISTRATIONS: Cell<(u64, u32)> = Cell::new((0, 0));
+}
+
+/// Resets the scanning flag when a scanning round ends, even if it ends with a trap.
+struct ScanGuard;
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        __SCANNING.with(|s| s.set(false));
+    }
+}
+
+/// Start the timer which periodically scans the registered addresses for new deposits.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(SCAN_TIMER_INTERVAL, || ic_cdk::spawn(scan_deposits()));
+}
+
+/// Register an address for automatic deposit detection.
+///
+/// Registering an address which is already registered restarts its idle period.
+pub fn register_address(eth_address: &str) {
+    let now = crate::time();
+    state::mutate_state(|state| {
+        state.deposit_scans.insert(
+            eth_address.to_string(),
+            DepositScan {
+                last_activity_time: now,
+                next_scan_time: now + MIN_SCAN_BACKOFF,
+                attempts: 0,
+            },
+        );
+    });
+}
+
+/// Register an address for automatic deposit detection for a caller who may not own it.
+///
+/// As anyone can do this for any address, a new address is only registered while fewer than
+/// `MAX_UNAUTHENTICATED_SCANS` addresses are registered, and for at most
+/// `MAX_UNAUTHENTICATED_REGISTRATIONS` addresses per `SCAN_TIMER_INTERVAL`. A registered address
+/// is left as is, so that its idle period cannot be restarted by anyone. Returns whether the
+/// address is registered.
+pub fn register_unauthenticated_address(eth_address: &str) -> bool {
+    let (registered, full) = state::read_state(|state| {
+        (
+            state.deposit_scans.contains_key(eth_address),
+            state.deposit_scans.len() >= MAX_UNAUTHENTICATED_SCANS,
+        )
+    });
+    if registered {
+        return true;
+    }
+    if full {
+        return false;
+    }
+    let now = crate::time();
+    let admitted = __UNAUTHENTICATED_REGISTRATIONS.with(|registrations| {
+        let (mut start, mut count) = registrations.get();
+        if now >= start.saturating_add(SCAN_TIMER_INTERVAL.as_nanos() as u64) {
+            (start, count) = (now, 0);
+        }
+        let admitted = count < MAX_UNAUTHENTICATED_REGISTRATIONS;
+        if admitted {
+            count += 1;
+        }
+        registrations.set((start, count));
+        admitted
+    });
+    if admitted {
+        register_address(eth_address);
+    }
+    admitted
+}
+
+fn backoff(attempts: u32) -> u64 {
+    MIN_SCAN_BACKOFF
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_SCAN_BACKOFF)
+}
+
+/// Call `update_balance` of the ckBTC minter for the registered addresses which are due.
+///
+/// An address is scanned again later with an exponential backoff until a deposit is credited,
+/// and is dropped after it has been idle for `deposit_scan_idle_period`.
+async fn scan_deposits() {
+    if crate::cycles::check_cycles_balance() || __SCANNING.with(|s| s.replace(true)) {
+        return;
+    }
+    let _guard = ScanGuard;
+    let now = crate::time();
+    let due_addresses: Vec<String> = state::mutate_state(|state| {
+        let idle_period = state.deposit_scan_idle_period;
+        state
+            .deposit_scans
+            .retain(|_, scan| now < scan.last_activity_time.saturating_add(idle_period));
+        state
+            .deposit_scans
+            .iter()
+            .filter(|(_, scan)| scan.next_scan_time <= now)
+            .take(MAX_SCANS_PER_ROUND)
+            .map(|(eth_address, _)| eth_address.clone())
+            .collect()
+    });
+    for eth_address in due_addresses {
+        let credited = crate::process_balance_update(&eth_address)
+            .await
+            .unwrap_or(0);
+        let now = crate::time();
+        state::mutate_state(|state| {
+            if let Some(scan) = state.deposit_scans.get_mut(&eth_address) {
+                if credited > 0 {
+                    scan.last_activity_time = now;
+                    scan.attempts = 0;
+                } else {
+                    scan.attempts = scan.attempts.saturating_add(1);
+                }
+                scan.next_scan_time = now + backoff(scan.attempts);
+            }
+        });
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{errors::VerifySignatureError, keccak256, state::SiweConfig};
+use candid::Principal;
+use libsecp256k1::{Message, RecoveryId, Signature};
+
+/// The EIP-4361 message signed by the owner of an Ethereum address to open a session for
+/// a principal.
+pub fn login_message(
+    config: &SiweConfig,
+    eth_address: &str,
+    session_principal: &Principal,
+    nonce: &str,
+    issued_at: u64,
+    expires_at: u64,
+) -> String {
+    format!(
+        "{domain} wants you to sign in with your Ethereum account:\n\
+         {address}\n\
+         \n\
+         Allow the principal {principal} to act for this account in the BTC Staking Pool.\n\
+         \n\
+         URI: {uri}\n\
+         Version: 1\n\
+         Chain ID: {chain_id}\n\
+         Nonce: {nonce}\n\
+         Issued At: {issued_at}\n\
+         Expiration Time: {expiration}",
+        domain = config.domain,
+        address = eip55_address(eth_address),
+        principal = session_principal,
+        expiration = format_rfc3339(expires_at),
+        uri = config.uri,
+        chain_id = config.chain_id,
+        nonce = nonce,
+        issued_at = format_rfc3339(issued_at),
+    )
+}
+
+/// The EIP-55 mixed-case checksum encoding of an Ethereum address given in lower case hex.
+fn eip55_address(eth_address: &str) -> String {
+    let hash = keccak256(eth_address.as_bytes());
+    let checksummed: String = eth_address
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+/// Recover the Ethereum address, in lower case hex, which signed a message with EIP-191
+/// `personal_sign`.
+pub fn recover_personal_sign_address(
+    message: &str,
+    signature: &[u8],
+) -> Result<String, VerifySignatureError> {
+    if signature.len() != 65 {
+        return Err(VerifySignatureError::InvalidSignatureLength);
+    }
+    let prefixed = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let message = Message::parse(&keccak256(prefixed.as_bytes()));
+    // Wallets set the recovery id to 27 or 28.
+    let recid = RecoveryId::parse_rpc(signature[64])
+        .or_else(|_| RecoveryId::parse(signature[64]))
+        .map_err(|e| VerifySignatureError::InvalidRecoveryIdInSignature(format!("{:?}", e)))?;
+    let signature = Signature::parse_standard_slice(&signature[..64])
+        .map_err(|e| VerifySignatureError::FailedParsingSignature(format!("{:?}", e)))?;
+    let public_key = libsecp256k1::recover(&message, &signature, &recid)
+        .map_err(|e| VerifySignatureError::FailedRecoveringPublicKey(format!("{:?}", e)))?;
+    let hash = keccak256(&public_key.serialize()[1..]);
+    Ok(hex::encode(&hash[12..]))
+}
+
+/// Format a time in nano seconds since the epoch as an RFC 3339 UTC date and time.
+fn format_rfc3339(time: u64) -> String {
+    let seconds = time / 1_000_000_000;
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+    // Convert the days since the epoch to a civil date, as described in
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        seconds_of_day % 3_600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// The lower case hex, without the `0x` prefix, of an Ethereum address, which is how the
+/// Ethereum address of a staker is keyed.
+pub fn normalize_eth_address(eth_address: &str) -> Option<String> {
+    let hex = eth_address.strip_prefix("0x").unwrap_or(eth_address);
+    (hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| hex.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECONDS: u64 = 1_000_000_000;
+
+    #[test]
+    fn eip55_address_of_specification_examples() {
+        for expected in [
+            // All caps.
+            "0x52908400098527886E0F7030069857D2E4169EE7",
+            "0x8617E340B3D01FA5F11F306F4090FD50E238070D",
+            // All lower.
+            "0xde709f2102306220921060314715629080e2fb77",
+            "0x27b1fdb04752bbc536007a920d24acb045561c26",
+            // Normal.
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ] {
+            let eth_address = normalize_eth_address(expected).unwrap();
+            assert_eq!(eip55_address(&eth_address), expected);
+        }
+    }
+
+    #[test]
+    fn format_rfc3339_of_known_dates() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_rfc3339(1_709_251_199 * SECONDS),
+            "2024-02-29T23:59:59Z"
+        );
+        // 2100 is not a leap year.
+        assert_eq!(
+            format_rfc3339(4_107_542_400 * SECONDS),
+            "2100-03-01T00:00:00Z"
+        );
+        // The nano seconds are truncated.
+        assert_eq!(format_rfc3339(SECONDS - 1), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn login_message_follows_eip4361() {
+        let config = SiweConfig {
+            domain: "app.example.com".to_string(),
+            uri: "https://app.example.com".to_string(),
+            chain_id: 1,
+            session_duration: 3_600 * SECONDS,
+        };
+        let message = login_message(
+            &config,
+            "5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            &Principal::anonymous(),
+            "abcdef12",
+            1_709_251_199 * SECONDS,
+            1_709_254_799 * SECONDS,
+        );
+        assert_eq!(
+            message,
+            "app.example.com wants you to sign in with your Ethereum account:\n\
+             0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed\n\
+             \n\
+             Allow the principal 2vxsx-fae to act for this account in the BTC Staking Pool.\n\
+             \n\
+             URI: https://app.example.com\n\
+             Version: 1\n\
+             Chain ID: 1\n\
+             Nonce: abcdef12\n\
+             Issued At: 2024-02-29T23:59:59Z\n\
+             Expiration Time: 2024-03-01T00:59:59Z"
+        );
+    }
+
+    #[test]
+    fn recover_personal_sign_address_of_known_signature() {
+        // Signed with `personal_sign` by the private key 1, using alloy's `PrivateKeySigner`.
+        let signature = hex::decode(
+            "88ec0d57d77c5feb1fd80a8933cf9afb94fea09e5c6d5746f0918022507b71df\
+             019531364109b8b280caeba53a2a60ddb1ef2f20a2ea594834d8d76d9929cb2f1b",
+        )
+        .unwrap();
+        let message = "Sign in to the BTC Staking Pool";
+        assert_eq!(
+            recover_personal_sign_address(message, &signature).unwrap(),
+            "7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+        // The recovery id may also be given as 0 or 1.
+        let mut raw_recovery_id = signature.clone();
+        raw_recovery_id[64] -= 27;
+        assert_eq!(
+            recover_personal_sign_address(message, &raw_recovery_id).unwrap(),
+            "7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+        // Another message recovers another address.
+        assert_ne!(
+            recover_personal_sign_address("Sign in", &signature).ok(),
+            Some("7e5f4552091a69125d5dfcb7b8c2659029395bdf".to_string())
+        );
+        assert!(matches!(
+            recover_personal_sign_address(message, &signature[..64]),
+            Err(VerifySignatureError::InvalidSignatureLength)
+        ));
+    }
+}
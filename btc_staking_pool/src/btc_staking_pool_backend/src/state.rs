@@ -1,7 +1,10 @@
-use alloc::collections::{BTreeMap, VecDeque};
+use crate::icrc::Account;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
 use candid::{CandidType, Deserialize, Principal};
+use ic_btc_interface::Network;
 use ic_ledger_types::Subaccount;
 use serde::Serialize;
+use serde_bytes::ByteBuf;
 use std::cell::RefCell;
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -11,6 +14,43 @@ pub struct Staker {
     pub tx_nonce: u64,
     pub ckbtc_balance: u64,
     pub otbtc_balance: u64,
+    pub deposits: Vec<Deposit>,
+    pub btc_deposit_address: Option<String>,
+    /// Whether the Ethereum address is a smart contract wallet, whose signatures are checked by
+    /// the contract (EIP-1271).
+    pub is_contract_wallet: bool,
+}
+
+impl Staker {
+    pub fn new(eth_address: String, subaccount: Subaccount) -> Self {
+        Self {
+            eth_address,
+            subaccount,
+            tx_nonce: 0,
+            ckbtc_balance: 0,
+            otbtc_balance: 0,
+            deposits: Vec::new(),
+            btc_deposit_address: None,
+            is_contract_wallet: false,
+        }
+    }
+}
+
+/// A BTC deposit for which the ckBTC minter minted ckBTC to the subaccount of a staker.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Deposit {
+    /// The id of the Bitcoin transaction, as shown by block explorers.
+    pub txid: String,
+    /// The output index of the UTXO in the Bitcoin transaction.
+    pub vout: u32,
+    /// The value of the UTXO, in satoshis.
+    pub value: u64,
+    /// The minted amount (UTXO value minus fees).
+    pub minted_amount: u64,
+    /// The MINT transaction index on the ckBTC ledger.
+    pub block_index: u64,
+    /// The time when the deposit was recorded by the pool.
+    pub time: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -20,6 +60,311 @@ pub struct UnstakeRequest {
     pub unlock_time: u64,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct EcdsaPublicKey {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+/// The automatic deposit detection of an address which requested a BTC deposit address.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DepositScan {
+    /// The time of the registration of the address or of the last credited deposit.
+    pub last_activity_time: u64,
+    pub next_scan_time: u64,
+    /// The number of scans without any credited deposit, which determines the backoff.
+    pub attempts: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ProtocolFeeConfig {
+    /// The protocol fee charged on the staking rewards, in basis points.
+    pub reward_fee_bps: u16,
+    /// The protocol fee charged on the BTC withdrawals, in basis points.
+    pub withdrawal_fee_bps: u16,
+    /// The principal allowed to withdraw from the treasury.
+    pub treasury_owner: Option<Principal>,
+}
+
+/// The minimum amounts of the operations, in e8s.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MinimumAmounts {
+    pub stake: u64,
+    pub unstake: u64,
+    pub withdraw_btc: u64,
+    pub transfer_otbtc: u64,
+    pub export_otbtc: u64,
+    pub withdraw_ckbtc: u64,
+    pub bridge_otbtc: u64,
+}
+
+/// The limits of staking, to limit the exposure of the pool.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct StakingCaps {
+    /// The maximum of `total_ckbtc_in_pool`.
+    pub max_total_ckbtc_in_pool: Option<u64>,
+    /// The maximum staked amount of an Ethereum address.
+    pub max_stake_per_staker: Option<u64>,
+    /// Whether only the Ethereum addresses in the allowlist can stake.
+    pub allowlist_enabled: bool,
+}
+
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum OperationKind {
+    Stake,
+    Unstake,
+    UnlockTokens,
+    WithdrawBtc,
+    TransferOtbtc,
+    ExportOtbtc,
+    WithdrawCkbtc,
+    BridgeOtbtc,
+    RedeemOtbtc,
+    DistributeRewards,
+}
+
+/// A step of an operation, which is a transfer on a ledger.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum OperationStep {
+    /// Transfer the staked ckBTC from the subaccount of the staker to the main account.
+    TransferCkbtcToPool,
+    /// Mint otBTC to the subaccount of the staker.
+    MintOtbtc,
+    /// Return the staked ckBTC to the subaccount of the staker, as the minting failed.
+    ReturnCkbtc { cause: OperationError },
+    /// Burn the unstaked otBTC from the subaccount of the staker.
+    BurnOtbtc,
+    /// Transfer the unlocked ckBTC from the main account to the subaccount of the staker.
+    TransferCkbtcToStaker,
+    /// Burn the withdrawn ckBTC from the subaccount of the staker.
+    BurnCkbtc,
+    /// Transfer the protocol fee of a withdrawal to the treasury subaccount.
+    TransferProtocolFee,
+    /// Transfer otBTC from the subaccount of the staker to the subaccount of the recipient.
+    TransferOtbtcToRecipient,
+    /// Transfer otBTC from the subaccount of the staker to an account outside the pool.
+    TransferOtbtcToAccount,
+    /// Transfer the withdrawn ckBTC from the subaccount of the staker to an account outside the
+    /// pool.
+    TransferCkbtcToAccount,
+    /// Lock otBTC by transferring it from the subaccount of the staker to the bridge subaccount.
+    TransferOtbtcToBridge,
+    /// Unlock otBTC by transferring it from the bridge subaccount to the subaccount of the
+    /// staker, as the ERC-20 tokens have been burnt on Ethereum.
+    TransferOtbtcFromBridge,
+}
+
+/// Where an operation sends tokens, other than the subaccount of the staker and the accounts of
+/// the pool.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// The subaccount of another staker.
+    Staker(String),
+    /// An account outside the pool.
+    Account(Account),
+    /// The ERC-20 contract of the bridge minting the bridged otBTC on Ethereum, as configured
+    /// when the operation was created.
+    Bridge {
+        erc20_contract: String,
+        chain_id: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum OperationStatus {
+    InProgress,
+    Completed,
+    /// Failed without any change.
+    Failed(OperationError),
+    /// Failed after some steps, which have been compensated.
+    Compensated(OperationError),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum OperationError {
+    /// The call to the ckBTC ledger canister failed.
+    CkbtcLedgerError(String),
+    /// The transfer on the ckBTC ledger canister failed.
+    CkbtcTransferError(String),
+    /// The call to the otBTC ledger canister failed.
+    OtbtcLedgerError(String),
+    /// The transfer on the otBTC ledger canister failed.
+    OtbtcTransferError(String),
+}
+
+/// An operation of a staker consisting of several ledger transfers, which is journaled so that
+/// it can be completed or compensated after an interruption.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Operation {
+    pub id: u64,
+    pub kind: OperationKind,
+    pub eth_address: String,
+    pub subaccount: Subaccount,
+    pub amount: u64,
+    /// The ledger fee paid by the staker.
+    pub fee: u64,
+    pub protocol_fee: u64,
+    /// Where the tokens are sent, chosen by the staker.
+    pub destination: Option<Destination>,
+    /// The memo of the transfers, which encodes the action and the nonce of the operation.
+    pub memo: u64,
+    /// The next step to be executed.
+    pub step: OperationStep,
+    /// The time when the current step was first attempted, which is the creation time of its
+    /// transfer.
+    pub step_created_at: u64,
+    pub status: OperationStatus,
+    /// The number of failed attempts of the current step.
+    pub attempts: u32,
+    /// Whether an attempt of the current step failed without an answer of the ledger, so that
+    /// its transfer may have gone through.
+    pub step_outcome_unknown: bool,
+    /// Whether the operation failed at a step which can neither be retried nor compensated
+    /// automatically, and is left to the controllers, see `resolve_operation`.
+    pub needs_operator: bool,
+    pub last_error: Option<OperationError>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// What a transfer of the pool is for, see `PoolTransfer`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolTransferKind {
+    /// A withdrawal from the treasury, whose amount and fee are reserved out of
+    /// `treasury_balance`.
+    WithdrawTreasury,
+    /// The protocol fee of a distribution of rewards, whose amount and fee are reserved in
+    /// `total_ckbtc_in_pool`.
+    ProtocolFee,
+}
+
+/// A ckBTC transfer of the pool which is not part of an operation of a staker, recorded until
+/// the ckBTC ledger answers.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PoolTransfer {
+    pub kind: PoolTransferKind,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: u64,
+    pub fee: u64,
+    pub memo: u64,
+    /// The creation time of the transfer, which is the same for every attempt.
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum StakerLock {
+    Operation(u64),
+    BalanceSync,
+}
+
+/// The counters of the operations of a kind, exported as metrics.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct OperationMetrics {
+    pub succeeded: u64,
+    pub failed: u64,
+    /// The time of the last failed step, in nano seconds.
+    pub last_error_time: Option<u64>,
+}
+
+/// The parameters of the Sign-In with Ethereum messages.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SiweConfig {
+    /// The domain of the dapp requesting the sign-in, e.g. `app.example.com`.
+    pub domain: String,
+    /// The URI of the dapp requesting the sign-in, e.g. `https://app.example.com`.
+    pub uri: String,
+    pub chain_id: u64,
+    /// How long a session lasts after the sign-in, in nano seconds.
+    pub session_duration: u64,
+}
+
+/// A Sign-In with Ethereum message which was prepared for a principal and is waiting for the
+/// signature of the Ethereum address.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SiweLogin {
+    pub eth_address: String,
+    pub message: String,
+    /// The time until which the message can be signed in with, in nano seconds.
+    pub login_deadline: u64,
+    /// The expiry of the session opened by the message, in nano seconds.
+    pub session_expires_at: u64,
+}
+
+/// A session in which a principal acts for an Ethereum address.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SiweSession {
+    pub eth_address: String,
+    /// The expiry of the session, in nano seconds.
+    pub expires_at: u64,
+}
+
+/// The EVM RPC canister used to verify the signatures of smart contract wallets (EIP-1271).
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Eip1271Config {
+    /// The EVM RPC canister, or a stand-in exposing the same `request` method.
+    pub evm_rpc_canister: Principal,
+    pub chain_id: u64,
+    /// The cycles attached to a request.
+    pub cycles: u128,
+    pub max_response_bytes: u64,
+}
+
+/// The EIP-712 domain of the stake attestations, which binds them to the contract checking them
+/// on a chain.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AttestationConfig {
+    pub chain_id: u64,
+    /// The address of the contract checking the attestations.
+    pub verifying_contract: String,
+}
+
+/// The otBTC balance of an Ethereum address, signed by the threshold ECDSA key of the pool.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct StakeAttestation {
+    pub eth_address: String,
+    pub otbtc_balance: u64,
+    /// A number increasing with every attestation of the pool.
+    pub nonce: u64,
+    /// The time of the attestation, in seconds.
+    pub timestamp: u64,
+    /// The ABI encoding of `(address account, uint256 otbtcBalance, uint256 nonce,
+    /// uint256 timestamp)`.
+    pub message: Vec<u8>,
+    /// The EIP-712 hash of the attestation, see `attestation::digest`.
+    pub digest: Vec<u8>,
+    /// The signature `r || s || v` of the digest, see `eth_signer::sign_digest`.
+    pub signature: Vec<u8>,
+}
+
+/// The lock-and-mint bridge of otBTC to an ERC-20 contract on Ethereum.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BridgeConfig {
+    /// The address of the ERC-20 contract, which mints with the vouchers signed by the pool.
+    pub erc20_contract: String,
+    pub chain_id: u64,
+    /// The principal reporting the burns of the ERC-20 contract, e.g. an operator or a canister
+    /// reading the logs through the EVM RPC canister.
+    pub operator: Principal,
+}
+
+/// The authorization of the ERC-20 contract to mint the otBTC locked by a staker.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BridgeVoucher {
+    /// The id of the operation which locked the otBTC.
+    pub nonce: u64,
+    /// The Ethereum address receiving the ERC-20 tokens.
+    pub eth_address: String,
+    pub amount: u64,
+    /// The ABI encoding of `(address token, uint256 chain_id, address recipient,
+    /// uint256 amount, uint256 nonce)`.
+    pub message: Vec<u8>,
+    /// The signature `r || s || v` of the message, see `eth_signer::sign`, once signed.
+    pub signature: Option<Vec<u8>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BtcStakingPoolState {
     pub ckbtc_minting_account: Principal,
@@ -29,23 +374,74 @@ pub struct BtcStakingPoolState {
     pub unstaking_queue: VecDeque<UnstakeRequest>,
     pub total_ckbtc_in_pool: u64,
     pub unbonding_period: u64,
+    pub deposit_scans: BTreeMap<String, DepositScan>,
+    pub deposit_scan_idle_period: u64,
+    pub btc_network: Option<Network>,
+    pub ckbtc_minter_ecdsa_key_name: Option<String>,
+    pub ckbtc_minter_public_key: Option<EcdsaPublicKey>,
+    /// The fee of a transfer on the ckBTC ledger, synced from the ledger. Mints and burns are
+    /// free.
+    pub ckbtc_transfer_fee: u64,
+    /// The fee of a transfer on the otBTC ledger, synced from the ledger. Mints and burns are
+    /// free.
+    pub otbtc_transfer_fee: u64,
+    pub protocol_fee_config: ProtocolFeeConfig,
+    /// The ckBTC accrued in the treasury subaccount of the pool.
+    pub treasury_balance: u64,
+    pub minimum_amounts: MinimumAmounts,
+    /// The minimum amount of a BTC withdrawal, synced from the ckBTC minter.
+    pub retrieve_btc_min_amount: u64,
+    pub staking_caps: StakingCaps,
+    /// The Ethereum addresses allowed to stake, if `staking_caps.allowlist_enabled` is set.
+    pub staker_allowlist: BTreeSet<String>,
+    pub operations: BTreeMap<u64, Operation>,
+    pub next_operation_id: u64,
+    /// The transfers of the pool whose outcome is unknown, by nonce, see
+    /// `journal::run_pool_transfer`.
+    pub pool_transfers: BTreeMap<u64, PoolTransfer>,
+    /// The stakers which are locked by an unfinished operation or a balance sync.
+    pub staker_locks: BTreeMap<String, StakerLock>,
+    pub operation_metrics: BTreeMap<OperationKind, OperationMetrics>,
+    /// The cycles balance below which the non-essential work of the canister is paused.
+    pub cycles_low_water_mark: u128,
+    /// Whether the cycles balance was below the low-water mark at the last check.
+    pub cycles_low: bool,
+    /// Sign-In with Ethereum is disabled until it is configured.
+    pub siwe_config: Option<SiweConfig>,
+    /// The pending sign-ins, by the principal of the session.
+    pub siwe_logins: BTreeMap<Principal, SiweLogin>,
+    /// The sessions, by the principal acting for the Ethereum address.
+    pub siwe_sessions: BTreeMap<Principal, SiweSession>,
+    /// The signatures of smart contract wallets are rejected until this is configured.
+    pub eip1271_config: Option<Eip1271Config>,
+    /// The time of the latest signature check by each smart contract wallet, for each caller,
+    /// within the last `EIP1271_CHECK_INTERVAL`.
+    pub eip1271_last_checks: BTreeMap<(Principal, String), u64>,
+    /// The name of the threshold ECDSA key signing Ethereum messages, and the public key of the
+    /// pool derived from it.
+    pub eth_signer_key_name: Option<String>,
+    pub eth_signer_public_key: Option<Vec<u8>>,
+    /// The stake attestations are not signed until this is configured.
+    pub attestation_config: Option<AttestationConfig>,
+    pub next_attestation_nonce: u64,
+    /// The latest attestation of each Ethereum address, which is returned again while it is
+    /// recent and the balance is unchanged.
+    pub attestations: BTreeMap<String, StakeAttestation>,
+    /// The bridge is disabled until it is configured.
+    pub bridge_config: Option<BridgeConfig>,
+    /// The mint vouchers, by nonce.
+    pub bridge_vouchers: BTreeMap<u64, BridgeVoucher>,
+    /// The otBTC locked in the bridge subaccount, minus the amounts being unlocked.
+    pub bridged_otbtc: u64,
+    /// The burns of the ERC-20 contract which have been redeemed, by transaction hash and log
+    /// index.
+    pub redeemed_burns: BTreeSet<(String, u64)>,
 }
 
 thread_local! {
     static __STATE: RefCell<Option<BtcStakingPoolState>> = RefCell::default();
 }
 
-/// Take the current state.
-///
-/// After calling this function the state won't be initialized anymore.
-/// Panics if there is no state.
-pub fn take_state<F, R>(f: F) -> R
-where
-    F: FnOnce(BtcStakingPoolState) -> R,
-{
-    __STATE.with(|s| f(s.take().expect("State not initialized!")))
-}
-
 /// Mutates (part of) the current state using `f`.
 ///
 /// Panics if there is no state.
@@ -72,3 +468,16 @@ pub fn replace_state(state: BtcStakingPoolState) {
         *s.borrow_mut() = Some(state);
     });
 }
+
+/// Save the current state to stable memory, before an upgrade.
+pub fn save_to_stable_memory() {
+    let bytes = read_state(|state| serde_cbor::to_vec(state)).expect("failed to encode the state");
+    ic_cdk::storage::stable_save((ByteBuf::from(bytes),)).expect("failed to save the state");
+}
+
+/// Restore the state saved to stable memory, after an upgrade.
+pub fn restore_from_stable_memory() {
+    let (bytes,): (ByteBuf,) =
+        ic_cdk::storage::stable_restore().expect("failed to restore the state");
+    replace_state(serde_cbor::from_slice(&bytes).expect("failed to decode the state"));
+}
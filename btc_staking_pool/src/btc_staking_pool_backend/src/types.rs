@@ -1,13 +1,31 @@
+use crate::icrc::Account;
+use crate::log::LogLevel;
+use crate::state::BridgeVoucher;
 use candid::{CandidType, Deserialize, Principal};
-use ic_btc_interface::Utxo;
+use ic_btc_interface::{Network, Utxo};
 use ic_ledger_types::Subaccount;
 use serde::Serialize;
+use serde_bytes::ByteBuf;
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct InitArgs {
     pub ckbtc_minting_account: Principal,
     pub ckbtc_ledger_account: Principal,
     pub otbtc_ledger_account: Principal,
+    /// The period after which an address is no longer scanned for deposits automatically,
+    /// in nano seconds.
+    pub deposit_scan_idle_period: Option<u64>,
+    /// The Bitcoin network of the ckBTC minter.
+    pub btc_network: Option<Network>,
+    /// The name of the ECDSA key of the ckBTC minter. If it is set together with `btc_network`,
+    /// the BTC deposit addresses are derived locally instead of being requested from the minter.
+    pub ckbtc_minter_ecdsa_key_name: Option<String>,
+    /// The fee of a transfer on the ckBTC ledger until it is synced from the ledger, see
+    /// `sync_minter_info`.
+    pub ckbtc_transfer_fee: Option<u64>,
+    /// The fee of a transfer on the otBTC ledger until it is synced from the ledger, see
+    /// `sync_minter_info`.
+    pub otbtc_transfer_fee: Option<u64>,
 }
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -50,6 +68,24 @@ pub enum UtxoStatus {
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct UpdateBalanceResponse(pub Vec<UtxoStatus>);
 
+/// The result of the `get_minter_info` endpoint of the ckBTC minter.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MinterInfo {
+    /// The minimal number of confirmations of a deposited UTXO.
+    pub min_confirmations: u32,
+    /// The minimal amount of a BTC withdrawal.
+    pub retrieve_btc_min_amount: u64,
+    /// The fee of the KYT check of a deposit or a withdrawal.
+    pub kyt_fee: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GetDepositsArgs {
+    pub eth_address: String,
+    pub offset: u64,
+    pub limit: u64,
+}
+
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct StakeArgs {
     pub eth_address: String,
@@ -57,6 +93,17 @@ pub struct StakeArgs {
     pub signature: Vec<u8>,
 }
 
+/// The result of the [stake] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct StakeResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The staked amount, which is also the minted amount of otBTC.
+    pub amount: u64,
+    /// The ckBTC ledger fee paid by the staker on top of the staked amount.
+    pub fee: u64,
+}
+
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct UnstakeArgs {
     pub eth_address: String,
@@ -64,9 +111,255 @@ pub struct UnstakeArgs {
     pub signature: Vec<u8>,
 }
 
+/// The result of the [unstake] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct UnstakeResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The burned amount of otBTC, which is queued for unlocking.
+    pub amount: u64,
+    /// The fee paid by the staker. Burning otBTC is free.
+    pub fee: u64,
+}
+
+/// The result of the [unlock_tokens_in_queue] endpoint, for the unlocked request.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct UnlockTokensResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    pub eth_address: String,
+    /// The amount of ckBTC credited to the staker.
+    pub amount: u64,
+    /// The ckBTC ledger fee paid by the staker out of the unstaked amount.
+    pub fee: u64,
+}
+
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct WithdrawBtcArgs {
     pub eth_address: String,
     pub amount: u64,
     pub signature: Vec<u8>,
 }
+
+/// The result of the [withdraw_btc] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WithdrawBtcResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The burned amount of ckBTC, which is the withdrawn amount minus the protocol fee.
+    pub amount: u64,
+    /// The ckBTC ledger fee paid by the staker for moving the protocol fee to the treasury,
+    /// on top of the withdrawn amount. Burning ckBTC is free.
+    pub fee: u64,
+    /// The protocol fee moved to the treasury, out of the withdrawn amount.
+    pub protocol_fee: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TransferOtbtcArgs {
+    /// The address of the sending staker.
+    pub from: String,
+    /// The address of the receiving staker, which is created if it does not exist.
+    pub to: String,
+    pub amount: u64,
+    pub signature: Vec<u8>,
+}
+
+/// The result of the [transfer_otbtc] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TransferOtbtcResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The amount of otBTC received by the recipient.
+    pub amount: u64,
+    /// The otBTC ledger fee paid by the sender, on top of the transferred amount.
+    pub fee: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExportOtbtcArgs {
+    pub eth_address: String,
+    pub amount: u64,
+    /// The account outside the pool receiving the otBTC.
+    pub to: Account,
+    pub signature: Vec<u8>,
+}
+
+/// The result of the [export_otbtc] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExportOtbtcResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The amount of otBTC received by the account.
+    pub amount: u64,
+    /// The otBTC ledger fee paid by the staker, on top of the exported amount.
+    pub fee: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ImportOtbtcArgs {
+    /// The address of the staker to credit.
+    pub eth_address: String,
+    /// The amount of otBTC approved by the caller for the pool.
+    pub amount: u64,
+    /// The subaccount of the caller holding the otBTC, if any.
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DepositCkbtcArgs {
+    /// The address of the staker to credit.
+    pub eth_address: String,
+    /// The amount of ckBTC approved by the caller for the pool.
+    pub amount: u64,
+    /// The subaccount of the caller holding the ckBTC, if any.
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WithdrawCkbtcArgs {
+    pub eth_address: String,
+    pub amount: u64,
+    /// The account outside the pool receiving the ckBTC.
+    pub to: Account,
+    pub signature: Vec<u8>,
+}
+
+/// The result of the [withdraw_ckbtc] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WithdrawCkbtcResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The amount of ckBTC received by the account, which is the withdrawn amount minus the
+    /// protocol fee.
+    pub amount: u64,
+    /// The ckBTC ledger fees paid by the staker, on top of the withdrawn amount.
+    pub fee: u64,
+    /// The protocol fee moved to the treasury, out of the withdrawn amount.
+    pub protocol_fee: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BridgeOtbtcArgs {
+    pub eth_address: String,
+    pub amount: u64,
+    pub signature: Vec<u8>,
+}
+
+/// The result of the [bridge_otbtc_to_eth] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct BridgeOtbtcResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The otBTC ledger fee paid by the staker, on top of the locked amount.
+    pub fee: u64,
+    /// The signed voucher to submit to the ERC-20 contract.
+    pub voucher: BridgeVoucher,
+}
+
+/// A burn of the ERC-20 tokens on Ethereum, as reported by the operator of the bridge.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BurnProof {
+    /// The hash of the Ethereum transaction which burnt the tokens.
+    pub tx_hash: String,
+    /// The index of the burn event in the logs of the block.
+    pub log_index: u64,
+    /// The Ethereum address which burnt the tokens, and receives the otBTC.
+    pub eth_address: String,
+    pub amount: u64,
+}
+
+/// The result of the [redeem_from_eth] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RedeemFromEthResponse {
+    /// The id of the operation, see [get_operation].
+    pub operation_id: u64,
+    /// The amount of otBTC unlocked into the subaccount of the staker.
+    pub amount: u64,
+    /// The otBTC ledger fee, out of the burnt amount.
+    pub fee: u64,
+}
+
+/// The result of the [distribute_rewards] endpoint.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DistributeRewardsResponse {
+    /// The ckBTC rewards found in the main account.
+    pub rewards: u64,
+    /// The protocol fee moved to the treasury, out of the rewards.
+    pub protocol_fee: u64,
+    /// The ckBTC ledger fee paid for moving the protocol fee, out of the rewards.
+    pub fee: u64,
+    /// The amount staked on behalf of the stakers, by mints which are journaled as operations and
+    /// executed in the background.
+    pub distributed: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WithdrawTreasuryArgs {
+    pub amount: u64,
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+}
+
+/// The result of the [withdraw_treasury] endpoint.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WithdrawTreasuryResponse {
+    pub amount: u64,
+    /// The ckBTC ledger fee paid by the treasury on top of the withdrawn amount.
+    pub fee: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct StakerBalances {
+    pub ckbtc_balance: u64,
+    pub otbtc_balance: u64,
+}
+
+/// The result of the [get_staker] endpoint.
+///
+/// The balances of the staker (absent if the staker is unknown) and the pool totals can be
+/// verified by checking the certificate, then looking up `stakers/<eth_address>` and `pool` in
+/// the witness, and comparing its root hash with the certified data in the certificate.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GetStakerResponse {
+    pub eth_address: String,
+    pub balances: Option<StakerBalances>,
+    pub total_ckbtc_in_pool: u64,
+    pub treasury_balance: u64,
+    /// The certificate of the certified data of the canister, only returned by query calls.
+    pub certificate: Option<ByteBuf>,
+    /// The CBOR encoded hash tree of the certified values.
+    pub witness: ByteBuf,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CyclesBalance {
+    pub balance: u128,
+    /// The balance below which the non-essential work of the canister is paused.
+    pub low_water_mark: u128,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GetLogsArgs {
+    /// Only return the entries at or above this level.
+    pub min_level: Option<LogLevel>,
+    /// Only return the entries logged at or after this time, in nano seconds.
+    pub since: Option<u64>,
+}
+
+pub type HeaderField = (String, String);
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: ByteBuf,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: ByteBuf,
+}